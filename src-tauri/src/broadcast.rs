@@ -0,0 +1,114 @@
+use crate::ssh::{Error, SShMgr, SshContext, SshSessions};
+use serde::Serialize;
+use std::{
+    collections::BTreeSet,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
+use tauri::{async_runtime::Mutex, State};
+
+static BROADCAST_ID_MGR: AtomicU32 = AtomicU32::new(1);
+
+// 广播组, 组内任一终端的输入同时发送给其他成员
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct BroadcastGroup {
+    pub id: u32,
+    pub name: String,
+    pub members: BTreeSet<u32>,
+    pub paused: bool,
+}
+
+impl SshSessions {
+    // 与 id 同在未暂停广播组中的其他终端
+    pub fn broadcast_peers(&self, id: u32) -> Vec<Arc<Mutex<SshContext>>> {
+        let mut ids: BTreeSet<u32> = BTreeSet::new();
+        for g in self.groups.values() {
+            if !g.paused && g.members.contains(&id) {
+                ids.extend(g.members.iter());
+            }
+        }
+        ids.remove(&id);
+
+        ids.iter()
+            .filter_map(|k| self.contexts.get(k).cloned())
+            .collect()
+    }
+
+    fn group_mut(&mut self, group: u32) -> Result<&mut BroadcastGroup, Error> {
+        self.groups
+            .get_mut(&group)
+            .ok_or(anyhow::anyhow!("broadcast group not found:{group}").into())
+    }
+}
+
+#[tauri::command]
+pub async fn ssh_broadcast_create(
+    name: String,
+    members: Vec<u32>,
+    stat: State<'_, SShMgr>,
+) -> Result<u32, Error> {
+    let mut l = stat.lock().await;
+    let id = BROADCAST_ID_MGR.fetch_add(1, Ordering::Release);
+
+    let members = members
+        .into_iter()
+        .filter(|k| l.contexts.contains_key(k))
+        .collect();
+    l.groups.insert(
+        id,
+        BroadcastGroup {
+            id,
+            name,
+            members,
+            paused: false,
+        },
+    );
+    Ok(id)
+}
+
+#[tauri::command]
+pub async fn ssh_broadcast_delete(group: u32, stat: State<'_, SShMgr>) -> Result<(), Error> {
+    stat.lock().await.groups.remove(&group);
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn ssh_broadcast_add(group: u32, id: u32, stat: State<'_, SShMgr>) -> Result<(), Error> {
+    let mut l = stat.lock().await;
+    if !l.contexts.contains_key(&id) {
+        return Err(anyhow::anyhow!("ssh context not found").into());
+    }
+
+    l.group_mut(group)?.members.insert(id);
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn ssh_broadcast_remove(
+    group: u32,
+    id: u32,
+    stat: State<'_, SShMgr>,
+) -> Result<(), Error> {
+    stat.lock().await.group_mut(group)?.members.remove(&id);
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn ssh_broadcast_pause(
+    group: u32,
+    paused: bool,
+    stat: State<'_, SShMgr>,
+) -> Result<(), Error> {
+    stat.lock().await.group_mut(group)?.paused = paused;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn ssh_broadcast_list(stat: State<'_, SShMgr>) -> Result<Vec<BroadcastGroup>, Error> {
+    let l = stat.lock().await;
+    let mut groups: Vec<BroadcastGroup> = l.groups.values().cloned().collect();
+    groups.sort_by_key(|v| v.id);
+    Ok(groups)
+}
@@ -1,133 +1,451 @@
 use crate::{
-    server::ServerContext,
-    ssh::{into_essh, ssh_create_session, Error},
-    upload::ENT_FTM,
+    ssh::{into_essh, Error},
+    transfer::{transfer_wait, TransferJob, TransferKind, TRANSFER_ID_MGR},
+    upload::{
+        hash_local, hash_remote, local_mtime, remote_join, resume_offset, transfer_connect,
+        ErrorPolicy, Progress, SymlinkPolicy, TransferOptions,
+    },
 };
 use anyhow::Result;
-use async_ssh2_lite::{ssh2::FileStat, AsyncSftp, TokioTcpStream};
-use futures_util::AsyncReadExt;
-use serde_json::json;
-use std::{path::Path, time::Instant};
-use tauri::{Emitter, State};
-use tokio::io::AsyncWriteExt;
+use async_ssh2_lite::{AsyncSession, AsyncSftp, TokioTcpStream};
+use futures_util::{AsyncReadExt, AsyncSeekExt as _};
+use std::{
+    collections::HashSet,
+    io::SeekFrom,
+    path::{Component, Path, PathBuf},
+    sync::atomic::Ordering,
+    time::Instant,
+};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
 
 #[tauri::command]
 pub async fn ssh_download(
     id: String,
     local_path: String,
     remote_path: String,
+    options: Option<TransferOptions>,
+    transfer_id: Option<u32>,
     wnd: tauri::Window,
-    svr_ctx: State<'_, ServerContext>,
 ) -> Result<(), Error> {
     //println!("ssh_download id:{id}, local_path:{local_path}, remote_path:{remote_path}");
-    let id_key = id.parse::<u32>().map_err(into_essh)?;
-    let lsm = svr_ctx.lock().await;
-
-    let cfg = lsm.config.clone();
-    let server = lsm
-        .servers
-        .get(&id_key)
-        .ok_or(into_essh(anyhow::anyhow!("server not found")))?
-        .clone();
-
-    drop(lsm);
-
-    let json_d1 = json!({
-        "rate": 0,
-        "message": format!("正在链接:{}:{}", server.host, server.port),
-    });
-    wnd.emit(ENT_FTM, json_d1).ok();
-
-    let session = ssh_create_session(&server, &cfg).await.map_err(into_essh)?;
-    let sftp = session.sftp().await.map_err(into_essh)?;
-
-    let json_d2 = json!({
-        "rate": 0,
-        "message": "链接成功",
-    });
-    wnd.emit(ENT_FTM, json_d2).ok();
-
-    download_files(&wnd, sftp, &local_path, &remote_path)
+    let job = TransferJob {
+        kind: TransferKind::Download,
+        server_id: id,
+        local_path,
+        remote_path,
+        options: options.unwrap_or_default(),
+    };
+    // 前端通过 ssh_transfer_id 预先取得 id, 用于过滤进度消息和暂停、取消
+    let transfer_id =
+        transfer_id.unwrap_or_else(|| TRANSFER_ID_MGR.fetch_add(1, Ordering::Release));
+    transfer_wait(&wnd, transfer_id, job)
         .await
-        .map_err(into_essh)?;
-    Ok(())
+        .map_err(into_essh)
+}
+
+pub async fn download_start(
+    wnd: &tauri::Window,
+    transfer_id: u32,
+    id: &str,
+    local_path: &str,
+    remote_path: &str,
+    options: &TransferOptions,
+) -> Result<()> {
+    let id_key = id.parse::<u32>()?;
+    let (session, sftp) = transfer_connect(wnd, transfer_id, id_key).await?;
+    download_files(
+        wnd,
+        transfer_id,
+        &session,
+        sftp,
+        local_path,
+        remote_path,
+        options,
+    )
+    .await
+}
+
+#[derive(Default)]
+struct RemoteTree {
+    dirs: Vec<String>,
+    // 远端路径, 相对路径, 大小
+    files: Vec<(PathBuf, String, u64)>,
+    // 相对路径, 链接目标, 目标是否为目录
+    links: Vec<(String, PathBuf, bool)>,
+}
+
+// 链接目标必须是相对路径且不超出下载的目录树, 否则本地链接会指向其他位置.
+// 按字面计算, 因此目标不能经过其他重建的链接(如 b -> . 时的 b/..)
+fn link_in_tree(rel_path: &str, target: &Path, links: &HashSet<&str>) -> bool {
+    let mut parts: Vec<String> = rel_path.split('/').map(String::from).collect();
+    parts.pop();
+
+    let comps: Vec<Component> = target.components().collect();
+    for (i, c) in comps.iter().enumerate() {
+        match c {
+            Component::Normal(v) => {
+                parts.push(v.to_string_lossy().into_owned());
+                if i + 1 < comps.len() && links.contains(parts.join("/").as_str()) {
+                    return false;
+                }
+            }
+            Component::CurDir => {}
+            Component::ParentDir if !parts.is_empty() => {
+                parts.pop();
+            }
+            _ => return false,
+        }
+    }
+    true
+}
+
+// 单个条目出错按 on_error 处理
+async fn walk_remote(
+    sftp: &AsyncSftp<TokioTcpStream>,
+    root: &Path,
+    options: &TransferOptions,
+    failed: &mut Vec<String>,
+) -> Result<RemoteTree> {
+    let mut tree = RemoteTree::default();
+    let mut stack = vec![(root.to_path_buf(), String::new())];
+
+    let mut fail = |name: &str, e: anyhow::Error| -> Result<()> {
+        match options.on_error {
+            ErrorPolicy::Abort => Err(e),
+            ErrorPolicy::Continue => {
+                failed.push(format!("{name}: {e}"));
+                Ok(())
+            }
+        }
+    };
+
+    // 跟随链接时按真实路径去重, 避免目录循环
+    let mut visited = HashSet::new();
+    visited.insert(sftp.realpath(root).await?);
+
+    while let Some((dir, rel)) = stack.pop() {
+        let entries = match sftp.readdir(&dir).await {
+            Ok(v) => v,
+            Err(e) if !rel.is_empty() => {
+                fail(&rel, e.into())?;
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        for (path, mut stat) in entries {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let path = remote_join(&dir, &name);
+            let rel_path = match rel.is_empty() {
+                true => name.to_string(),
+                false => format!("{rel}/{name}"),
+            };
+
+            if stat.file_type().is_symlink() {
+                match options.symlinks {
+                    SymlinkPolicy::Skip => continue,
+                    SymlinkPolicy::Link => {
+                        let target = match sftp.readlink(&path).await {
+                            Ok(v) => v,
+                            Err(e) => {
+                                fail(&rel_path, e.into())?;
+                                continue;
+                            }
+                        };
+                        let is_dir = sftp.stat(&path).await.is_ok_and(|v| v.is_dir());
+                        tree.links.push((rel_path, target, is_dir));
+                        continue;
+                    }
+                    SymlinkPolicy::Follow => match sftp.stat(&path).await {
+                        Ok(v) => stat = v,
+                        // 失效的链接
+                        Err(e) => {
+                            fail(&rel_path, e.into())?;
+                            continue;
+                        }
+                    },
+                }
+            }
+
+            if stat.is_dir() {
+                let real = match sftp.realpath(&path).await {
+                    Ok(v) => v,
+                    Err(e) => {
+                        fail(&rel_path, e.into())?;
+                        continue;
+                    }
+                };
+                if !visited.insert(real) {
+                    continue;
+                }
+                tree.dirs.push(rel_path.clone());
+                stack.push((path, rel_path));
+            } else if stat.is_file() {
+                tree.files
+                    .push((path, rel_path, stat.size.unwrap_or_default()));
+            }
+        }
+    }
+
+    // 所有链接收集完后才能判断目标是否经过其他链接
+    let links = std::mem::take(&mut tree.links);
+    let names: HashSet<&str> = links.iter().map(|v| v.0.as_str()).collect();
+    let mut kept = Vec::new();
+    for (rel_path, target, is_dir) in links.iter() {
+        if link_in_tree(rel_path, target, &names) {
+            kept.push((rel_path.clone(), target.clone(), *is_dir));
+        } else {
+            let e = anyhow::anyhow!("link target outside tree:{}", target.display());
+            fail(rel_path, e)?;
+        }
+    }
+    tree.links = kept;
+
+    tree.dirs.sort();
+    Ok(tree)
+}
+
+// 已存在指向相同目标的链接时跳过, 指向其他位置的链接替换, 不删除普通文件和目录
+fn recreate_link(target: &Path, link: &Path, is_dir: bool) -> std::io::Result<()> {
+    match std::fs::symlink_metadata(link) {
+        Ok(v) if v.file_type().is_symlink() => {
+            if std::fs::read_link(link)? == target {
+                return Ok(());
+            }
+            remove_link(link)?;
+        }
+        Ok(_) => {
+            let e = std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a link", link.display()),
+            );
+            return Err(e);
+        }
+        Err(_) => {}
+    }
+    local_symlink(target, link, is_dir)
+}
+
+#[cfg(unix)]
+fn remove_link(link: &Path) -> std::io::Result<()> {
+    std::fs::remove_file(link)
+}
+
+// windows 的目录链接需要按目录删除
+#[cfg(windows)]
+fn remove_link(link: &Path) -> std::io::Result<()> {
+    std::fs::remove_file(link).or_else(|_| std::fs::remove_dir(link))
+}
+
+#[cfg(unix)]
+fn local_symlink(target: &Path, link: &Path, _is_dir: bool) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+// windows 需要按目标类型创建不同的链接
+#[cfg(windows)]
+fn local_symlink(target: &Path, link: &Path, is_dir: bool) -> std::io::Result<()> {
+    match is_dir {
+        true => std::os::windows::fs::symlink_dir(target, link),
+        false => std::os::windows::fs::symlink_file(target, link),
+    }
 }
 
 async fn download_files<P: AsRef<Path>>(
     wnd: &tauri::Window,
+    transfer_id: u32,
+    session: &AsyncSession<TokioTcpStream>,
     sftp: AsyncSftp<TokioTcpStream>,
     local: P,
     remote: P,
+    options: &TransferOptions,
 ) -> Result<()> {
+    let time = Instant::now();
     let local_path = local.as_ref();
     let remote_path = remote.as_ref();
 
-    let ft = sftp.lstat(remote_path).await?;
+    let ft = sftp.stat(remote_path).await?;
+    let name = remote_path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let local_root = local_path.join(name.as_ref());
+
+    if !ft.is_dir() {
+        let mut progress = Progress::new(transfer_id, 1, ft.size.unwrap_or_default());
+        download_onefile(
+            wnd,
+            session,
+            &sftp,
+            remote_path,
+            &local_root,
+            options,
+            &mut progress,
+        )
+        .await?;
+        progress.file_done();
+        progress.finish(wnd, &name, time);
+        return Ok(());
+    }
+
+    let mut failed: Vec<String> = Vec::new();
+    let tree = walk_remote(&sftp, remote_path, options, &mut failed).await?;
+    let total_size = tree.files.iter().map(|v| v.2).sum();
+    let mut progress = Progress::new(transfer_id, tree.files.len(), total_size);
+
+    tokio::fs::create_dir_all(&local_root).await?;
+    for d in tree.dirs.iter() {
+        if let Err(e) = tokio::fs::create_dir_all(local_root.join(d)).await {
+            match options.on_error {
+                ErrorPolicy::Abort => return Err(e.into()),
+                ErrorPolicy::Continue => failed.push(format!("{d}: {e}")),
+            }
+        }
+    }
+
+    for (rel, target, is_dir) in tree.links.iter() {
+        if let Err(e) = recreate_link(target, &local_root.join(rel), *is_dir) {
+            match options.on_error {
+                ErrorPolicy::Abort => return Err(e.into()),
+                ErrorPolicy::Continue => failed.push(format!("{rel}: {e}")),
+            }
+        }
+    }
 
-    if ft.is_dir() {
-        anyhow::bail!("remote_path is dir");
-    } else {
-        download_onefile(wnd, &sftp, local_path, remote_path, &ft).await?;
+    for (src, rel, _) in tree.files.iter() {
+        options.token.check()?;
+        let dst = local_root.join(rel);
+        match download_onefile(wnd, session, &sftp, src, &dst, options, &mut progress).await {
+            Ok(_) => progress.file_done(),
+            // 被取消或暂停时不按失败文件处理
+            Err(e)
+                if options.on_error == ErrorPolicy::Continue && options.token.check().is_ok() =>
+            {
+                failed.push(format!("{rel}: {e}"))
+            }
+            Err(e) => return Err(e),
+        }
     }
 
+    progress.finish(wnd, &name, time);
+    if !failed.is_empty() {
+        anyhow::bail!("{} failed: {}", failed.len(), failed.join("; "));
+    }
     Ok(())
 }
 
 async fn download_onefile(
     wnd: &tauri::Window,
+    session: &AsyncSession<TokioTcpStream>,
     sftp: &AsyncSftp<TokioTcpStream>,
-    local_path: &Path,
     remote_path: &Path,
-    ft: &FileStat,
+    local_file: &Path,
+    options: &TransferOptions,
+    progress: &mut Progress,
 ) -> Result<()> {
-    let time = Instant::now();
     let file_name = remote_path
         .file_name()
         .unwrap_or_default()
         .to_string_lossy();
-    let local_file = local_path.join(file_name.as_ref());
 
     let mut src = sftp.open(remote_path).await?;
-    let mut dst = tokio::fs::File::create(local_file).await?;
+    let mut offset = 0;
+    if options.resume {
+        let stat = src.stat().await?;
+        let src_meta = (
+            stat.size.unwrap_or_default(),
+            stat.mtime.unwrap_or_default(),
+        );
+        let dst = tokio::fs::metadata(local_file)
+            .await
+            .ok()
+            .map(|v| (v.len(), local_mtime(&v)));
+        let verify;
+        (offset, verify) = resume_offset(src_meta, dst, options.verify);
+
+        if verify {
+            progress.verifying(wnd, &file_name);
+            if hash_remote(session, sftp, remote_path, offset).await?
+                != hash_local(local_file, offset).await?
+            {
+                offset = 0;
+            }
+        }
+    }
+
+    let mut dst = match offset {
+        0 => tokio::fs::File::create(local_file).await?,
+        _ => {
+            let mut f = tokio::fs::OpenOptions::new()
+                .write(true)
+                .open(local_file)
+                .await?;
+            f.seek(SeekFrom::Start(offset)).await?;
+            f
+        }
+    };
+    src.seek(SeekFrom::Start(offset)).await?;
+    progress.skip(offset);
 
     let mut buf = vec![0u8; 256 * 1024];
     let data = buf.as_mut_slice();
-    let total_size = ft.size.unwrap_or_default();
-    let mut rate = 0;
-    let mut now_size = 0;
 
-    while let Ok(n) = src.read(data).await {
+    loop {
+        options.token.check()?;
+        let n = src.read(data).await?;
         if n == 0 {
             break;
         }
 
-        now_size += n as u64;
         dst.write_all(&data[..n]).await?;
+        progress.advance(wnd, n as u64, &file_name);
+    }
 
-        if total_size == 0 {
-            continue;
-        }
+    dst.flush().await?;
+    Ok(())
+}
 
-        let rate_tmp = now_size * 100 / total_size;
-        if rate_tmp == rate {
-            continue;
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        rate = rate_tmp;
-        let json_data = json!({
-            "rate": rate_tmp,
-            "message": file_name.to_string(),
-        });
-        wnd.emit(ENT_FTM, json_data).ok();
+    #[test]
+    fn link_targets() {
+        let none = HashSet::new();
+        assert!(link_in_tree("a", Path::new("b"), &none));
+        assert!(link_in_tree("d/a", Path::new("../b/./c"), &none));
+        assert!(link_in_tree("d/e/a", Path::new("../../b"), &none));
+        assert!(!link_in_tree("a", Path::new("../b"), &none));
+        assert!(!link_in_tree("d/a", Path::new("../../b"), &none));
+        assert!(!link_in_tree("d/a", Path::new("/etc/passwd"), &none));
     }
 
-    if now_size >= total_size {
-        let json_data = json!({
-            "rate": 100,
-            "message": format!("{}, time:{} ms, size:{}", file_name, time.elapsed().as_millis(), total_size),
-        });
-        wnd.emit(ENT_FTM, json_data).ok();
+    #[test]
+    fn link_through_link() {
+        let links = HashSet::from(["b", "a", "d/c"]);
+        assert!(link_in_tree("b", Path::new("."), &links));
+        // 目标本身是链接时由该链接自己的检查保证
+        assert!(link_in_tree("a", Path::new("b"), &links));
+        assert!(!link_in_tree("a", Path::new("b/.."), &links));
+        assert!(!link_in_tree("x", Path::new("d/c/y"), &links));
+        assert!(link_in_tree("x", Path::new("d/y"), &links));
     }
 
-    Ok(())
+    #[cfg(unix)]
+    #[test]
+    fn recreate_existing_link() {
+        let dir = std::env::temp_dir().join(format!("recreate_link_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let link = dir.join("l");
+
+        recreate_link(Path::new("a"), &link, false).unwrap();
+        recreate_link(Path::new("a"), &link, false).unwrap();
+        recreate_link(Path::new("b"), &link, false).unwrap();
+        assert_eq!(std::fs::read_link(&link).unwrap(), Path::new("b"));
+
+        let file = dir.join("f");
+        std::fs::write(&file, "data").unwrap();
+        assert!(recreate_link(Path::new("a"), &file, false).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,226 @@
+use crate::{
+    pool::{session_acquire, SessionPool},
+    prompt::{AuthPrompt, PromptMgr, PromptSink},
+    server::ServerContext,
+    ssh::{into_essh, Error},
+};
+use anyhow::Result;
+use async_ssh2_lite::{AsyncSession, TokioTcpStream};
+use futures_util::{AsyncRead, AsyncReadExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use tauri::{ipc::Channel, State};
+
+const DEFAULT_CONCURRENCY: usize = 8;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ExecOptions {
+    // 毫秒
+    #[serde(default = "ExecOptions::default_timeout")]
+    pub timeout: u64,
+    // stdout 和 stderr 各自最多保留的字节数, 超出部分丢弃
+    #[serde(default = "ExecOptions::default_max_output")]
+    pub max_output: usize,
+}
+
+impl Default for ExecOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Self::default_timeout(),
+            max_output: Self::default_max_output(),
+        }
+    }
+}
+
+impl ExecOptions {
+    fn default_timeout() -> u64 {
+        30000
+    }
+    fn default_max_output() -> usize {
+        1024 * 1024
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ExecResult {
+    pub exit_status: i32,
+    pub stdout: String,
+    pub stderr: String,
+    pub truncated: bool,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct HostResult {
+    id: String,
+    name: String,
+    #[serde(flatten)]
+    result: Option<ExecResult>,
+    // 毫秒, 包含建立会话的时间
+    duration: u64,
+    error: String,
+}
+
+// 读到 EOF, 超过上限的数据继续读取但丢弃, 避免远端阻塞
+async fn read_capped<R: AsyncRead + Unpin>(reader: &mut R, cap: usize) -> Result<(Vec<u8>, bool)> {
+    let mut data = Vec::new();
+    let mut buf = vec![0u8; 16 * 1024];
+    let mut truncated = false;
+
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+
+        let keep = n.min(cap - data.len());
+        data.extend_from_slice(&buf[..keep]);
+        truncated |= keep < n;
+    }
+
+    Ok((data, truncated))
+}
+
+pub async fn exec_command(
+    session: &AsyncSession<TokioTcpStream>,
+    command: &str,
+    options: &ExecOptions,
+) -> Result<ExecResult> {
+    let run = async {
+        let mut channel = session.channel_session().await?;
+        channel.exec(command).await?;
+
+        let mut stdout = channel.stream(0);
+        let mut stderr = channel.stream(1);
+        let (out, err) = tokio::try_join!(
+            read_capped(&mut stdout, options.max_output),
+            read_capped(&mut stderr, options.max_output),
+        )?;
+
+        channel.wait_close().await?;
+
+        Ok(ExecResult {
+            exit_status: channel.exit_status()?,
+            stdout: String::from_utf8_lossy(&out.0).to_string(),
+            stderr: String::from_utf8_lossy(&err.0).to_string(),
+            truncated: out.1 || err.1,
+        })
+    };
+
+    // 超时后 channel 随 future 一起释放
+    match tokio::time::timeout(Duration::from_millis(options.timeout), run).await {
+        Ok(ret) => ret,
+        Err(_) => anyhow::bail!("command timeout after {} ms", options.timeout),
+    }
+}
+
+#[tauri::command]
+pub async fn ssh_exec(
+    id: String,
+    command: String,
+    options: Option<ExecOptions>,
+    wnd: tauri::Window,
+    svr_ctx: State<'_, ServerContext>,
+    prompt_mgr: State<'_, PromptMgr>,
+    pool: State<'_, SessionPool>,
+) -> Result<ExecResult, Error> {
+    let id_key = id.parse::<u32>().map_err(into_essh)?;
+    let info = svr_ctx.lock().await.connect_info(id_key)?;
+
+    let prompt = AuthPrompt {
+        mgr: &prompt_mgr,
+        sink: PromptSink::Window(wnd),
+    };
+    let session = session_acquire(&pool, id_key, &info, &prompt).await?;
+
+    let options = options.unwrap_or_default();
+    exec_command(&session, &command, &options)
+        .await
+        .map_err(into_essh)
+}
+
+// 在分组或选中的服务器上并发执行, 每台完成后立即通过 on_result 返回结果
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+pub async fn ssh_exec_group(
+    group: Option<String>,
+    ids: Vec<String>,
+    command: String,
+    options: Option<ExecOptions>,
+    concurrency: Option<usize>,
+    on_result: Channel<serde_json::Value>,
+    wnd: tauri::Window,
+    svr_ctx: State<'_, ServerContext>,
+    prompt_mgr: State<'_, PromptMgr>,
+    pool: State<'_, SessionPool>,
+) -> Result<usize, Error> {
+    let mut targets: Vec<u32> = Vec::with_capacity(ids.len());
+    for v in ids.iter() {
+        targets.push(v.parse::<u32>().map_err(into_essh)?);
+    }
+
+    let server_mgr = svr_ctx.lock().await;
+    if let Some(group) = group.as_deref() {
+        targets.extend(
+            server_mgr
+                .servers
+                .iter()
+                .filter(|(_, v)| v.group == group)
+                .map(|(k, _)| *k),
+        );
+    }
+    targets.sort_unstable();
+    targets.dedup();
+
+    let hosts: Vec<_> = targets
+        .iter()
+        .map(|k| (*k, server_mgr.connect_info(*k)))
+        .collect();
+    drop(server_mgr);
+
+    let options = options.unwrap_or_default();
+    let prompt = AuthPrompt {
+        mgr: &prompt_mgr,
+        sink: PromptSink::Window(wnd),
+    };
+    let count = hosts.len();
+
+    futures_util::stream::iter(hosts)
+        .for_each_concurrent(
+            concurrency.unwrap_or(DEFAULT_CONCURRENCY).max(1),
+            |(k, info)| {
+                let (prompt, pool, options, command, on_result) =
+                    (&prompt, &pool, &options, &command, &on_result);
+                async move {
+                    let time = Instant::now();
+                    let mut host = HostResult {
+                        id: k.to_string(),
+                        ..Default::default()
+                    };
+
+                    let ret = match info {
+                        Ok(info) => {
+                            host.name.clone_from(&info.server.name);
+                            match session_acquire(pool, k, &info, prompt).await {
+                                Ok(session) => exec_command(&session, command, options).await,
+                                Err(e) => Err(e),
+                            }
+                        }
+                        Err(e) => Err(e),
+                    };
+
+                    match ret {
+                        Ok(v) => host.result = Some(v),
+                        Err(e) => host.error = e.to_string(),
+                    }
+                    host.duration = time.elapsed().as_millis() as u64;
+
+                    if let Ok(v) = serde_json::to_value(host) {
+                        on_result.send(v).ok();
+                    }
+                }
+            },
+        )
+        .await;
+
+    Ok(count)
+}
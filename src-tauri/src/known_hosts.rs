@@ -0,0 +1,493 @@
+use crate::{
+    server::ServerContext,
+    ssh::{into_essh, Error},
+};
+use anyhow::Result;
+use async_ssh2_lite::{ssh2::MethodType, AsyncSession, TokioTcpStream};
+use openssl::{
+    base64::{decode_block, encode_block},
+    hash::MessageDigest,
+    pkey::PKey,
+    sha::sha256,
+    sign::Signer,
+};
+use serde::Serialize;
+use std::{
+    env,
+    fmt::Display,
+    io::Write,
+    iter,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tauri::State;
+
+pub const KNOWN_HOSTS_FILE: &str = "known_hosts";
+
+static KNOWN_HOSTS_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "kind", rename = "host_key_changed")]
+pub struct HostKeyChanged {
+    pub host: String,
+    pub port: u16,
+    pub key_type: String,
+    // 与 key_type 不同时, 表示服务器提供了未记录过的公钥类型
+    pub old_key_type: String,
+    pub old_fingerprint: String,
+    pub new_fingerprint: String,
+    // 新公钥(base64), 用户确认后原样传回 ssh_trust_host_key
+    pub key: String,
+}
+
+impl Display for HostKeyChanged {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "host key changed for {}:{}, old:{} {} new:{} {}",
+            self.host,
+            self.port,
+            self.old_key_type,
+            self.old_fingerprint,
+            self.key_type,
+            self.new_fingerprint
+        )
+    }
+}
+
+impl std::error::Error for HostKeyChanged {}
+
+struct KnownHost {
+    marker: Option<String>,
+    patterns: String,
+    key_type: String,
+    key: String,
+}
+
+enum HostCheck {
+    Match,
+    // (记录的公钥类型, 指纹)
+    Mismatch(String, String),
+    Revoked,
+    NotFound,
+}
+
+fn host_entry(host: &str, port: u16) -> String {
+    match port {
+        22 => host.to_string(),
+        _ => format!("[{host}]:{port}"),
+    }
+}
+
+pub fn fingerprint(key: &[u8]) -> String {
+    let hash = encode_block(&sha256(key));
+    format!("SHA256:{}", hash.trim_end_matches('='))
+}
+
+// 公钥blob开头为 string 类型名, 如 ssh-ed25519
+fn key_type_name(key: &[u8]) -> Option<String> {
+    let len = u32::from_be_bytes(key.get(..4)?.try_into().ok()?) as usize;
+    let name = key.get(4..4 + len)?;
+    String::from_utf8(name.to_vec()).ok()
+}
+
+fn parse_known_hosts(content: &str) -> Vec<KnownHost> {
+    let mut hosts = Vec::new();
+
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line.split_whitespace();
+        let mut marker = None;
+        let mut patterns = fields.next().unwrap_or_default();
+        if patterns.starts_with('@') {
+            marker = Some(patterns.to_string());
+            patterns = fields.next().unwrap_or_default();
+        }
+
+        let (Some(key_type), Some(key)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+
+        hosts.push(KnownHost {
+            marker,
+            patterns: patterns.to_string(),
+            key_type: key_type.to_string(),
+            key: key.to_string(),
+        });
+    }
+
+    hosts
+}
+
+fn wildcard_match(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.first(), text.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            wildcard_match(&pattern[1..], text)
+                || (!text.is_empty() && wildcard_match(pattern, &text[1..]))
+        }
+        (Some(b'?'), Some(_)) => wildcard_match(&pattern[1..], &text[1..]),
+        (Some(p), Some(t)) if p.eq_ignore_ascii_case(t) => {
+            wildcard_match(&pattern[1..], &text[1..])
+        }
+        _ => false,
+    }
+}
+
+// |1|salt|hash 格式, hash = HMAC-SHA1(salt, host)
+fn hashed_match(pattern: &str, entry: &str) -> bool {
+    let mut parts = pattern.trim_start_matches("|1|").split('|');
+    let (Some(salt), Some(hash)) = (parts.next(), parts.next()) else {
+        return false;
+    };
+
+    let hmac = || -> Result<bool> {
+        let salt = decode_block(salt)?;
+        let hash = decode_block(hash)?;
+        let pkey = PKey::hmac(&salt)?;
+        let mut signer = Signer::new(MessageDigest::sha1(), &pkey)?;
+        Ok(signer.sign_oneshot_to_vec(entry.as_bytes())? == hash)
+    };
+
+    hmac().unwrap_or_default()
+}
+
+fn host_matches(patterns: &str, host: &str, port: u16) -> bool {
+    let entry = host_entry(host, port);
+    if patterns.starts_with("|1|") {
+        return hashed_match(patterns, &entry);
+    }
+
+    let mut matched = false;
+    for pattern in patterns.split(',') {
+        if let Some(negated) = pattern.strip_prefix('!') {
+            if wildcard_match(negated.as_bytes(), entry.as_bytes()) {
+                return false;
+            }
+        } else if wildcard_match(pattern.as_bytes(), entry.as_bytes()) {
+            matched = true;
+        }
+    }
+    matched
+}
+
+// 与 OpenSSH 相同, 先检查所有匹配的 @revoked 记录, 再比较普通记录.
+// 只记录了其他类型的公钥时同样视为变更, 由用户确认
+fn check_content(content: &str, host: &str, port: u16, key_type: &str, key: &[u8]) -> HostCheck {
+    let hosts: Vec<KnownHost> = parse_known_hosts(content)
+        .into_iter()
+        .filter(|kh| host_matches(&kh.patterns, host, port))
+        .collect();
+    let same = |kh: &KnownHost| decode_block(&kh.key).is_ok_and(|v| v == key);
+
+    if hosts
+        .iter()
+        .any(|kh| kh.marker.as_deref() == Some("@revoked") && same(kh))
+    {
+        return HostCheck::Revoked;
+    }
+
+    let plain: Vec<&KnownHost> = hosts.iter().filter(|kh| kh.marker.is_none()).collect();
+    if plain.iter().any(|kh| kh.key_type == key_type && same(kh)) {
+        return HostCheck::Match;
+    }
+
+    // 优先报告同类型的旧公钥
+    let old = plain
+        .iter()
+        .find(|kh| kh.key_type == key_type)
+        .or(plain.first());
+    match old {
+        Some(kh) => {
+            let old_key = decode_block(&kh.key).unwrap_or_default();
+            HostCheck::Mismatch(kh.key_type.clone(), fingerprint(&old_key))
+        }
+        None => HostCheck::NotFound,
+    }
+}
+
+fn check_file(path: &Path, host: &str, port: u16, key_type: &str, key: &[u8]) -> HostCheck {
+    let content = std::fs::read_to_string(path).unwrap_or_default();
+    check_content(&content, host, port, key_type, key)
+}
+
+// 只替换本主机同类型的普通记录, 保留 @revoked 等标记行和覆盖其他主机的通配记录
+fn replace_host(content: &str, host: &str, port: u16, key_type: &str) -> String {
+    let entry = host_entry(host, port);
+    let mut lines = String::with_capacity(content.len());
+
+    for line in content.lines() {
+        let keep = parse_known_hosts(line).first().is_none_or(|kh| {
+            kh.marker.is_some() || kh.patterns != entry || kh.key_type != key_type
+        });
+        if keep {
+            lines.push_str(line);
+            lines.push('\n');
+        }
+    }
+
+    lines
+}
+
+fn openssh_known_hosts() -> Option<PathBuf> {
+    let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"))?;
+    Some(PathBuf::from(home).join(".ssh").join("known_hosts"))
+}
+
+// ssh-rsa 公钥可通过 rsa-sha2-* 算法协商
+fn host_key_algs(key_type: &str) -> Vec<&str> {
+    match key_type {
+        "ssh-rsa" => vec!["rsa-sha2-512", "rsa-sha2-256", "ssh-rsa"],
+        _ => vec![key_type],
+    }
+}
+
+fn content_key_types(content: &str, host: &str, port: u16, types: &mut Vec<String>) {
+    for kh in parse_known_hosts(content) {
+        if kh.marker.is_none()
+            && host_matches(&kh.patterns, host, port)
+            && !types.contains(&kh.key_type)
+        {
+            types.push(kh.key_type);
+        }
+    }
+}
+
+// 握手前调用, 已记录的公钥类型排在前面, 避免服务器通过其他类型绕开已有记录
+pub async fn prefer_known_host_keys(
+    session: &AsyncSession<TokioTcpStream>,
+    app_path: &Path,
+    host: &str,
+    port: u16,
+) -> Result<()> {
+    let mut types = Vec::new();
+    {
+        let _guard = KNOWN_HOSTS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        for path in iter::once(app_path.join(KNOWN_HOSTS_FILE)).chain(openssh_known_hosts()) {
+            let content = std::fs::read_to_string(path).unwrap_or_default();
+            content_key_types(&content, host, port, &mut types);
+        }
+    }
+    if types.is_empty() {
+        return Ok(());
+    }
+
+    let supported = session.supported_algs(MethodType::HostKey).await?;
+    let mut prefs: Vec<&str> = types
+        .iter()
+        .flat_map(|v| host_key_algs(v))
+        .filter(|v| supported.contains(v))
+        .collect();
+    if prefs.is_empty() {
+        return Ok(());
+    }
+    for v in supported {
+        if !prefs.contains(&v) {
+            prefs.push(v);
+        }
+    }
+
+    session
+        .method_pref(MethodType::HostKey, &prefs.join(","))
+        .await?;
+    Ok(())
+}
+
+fn append_host(path: &Path, host: &str, port: u16, key_type: &str, key: &str) -> Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    writeln!(file, "{} {} {}", host_entry(host, port), key_type, key)?;
+    Ok(())
+}
+
+pub fn verify_host_key(
+    session: &AsyncSession<TokioTcpStream>,
+    app_path: &Path,
+    host: &str,
+    port: u16,
+) -> Result<()> {
+    let (key, _) = session
+        .host_key()
+        .ok_or(anyhow::anyhow!("{host}:{port} no host key"))?;
+    let key_type = key_type_name(key).ok_or(anyhow::anyhow!("{host}:{port} invalid host key"))?;
+
+    let _guard = KNOWN_HOSTS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let store = app_path.join(KNOWN_HOSTS_FILE);
+
+    let mut check = check_file(&store, host, port, &key_type, key);
+    if let (HostCheck::NotFound, Some(path)) = (&check, openssh_known_hosts()) {
+        check = check_file(&path, host, port, &key_type, key);
+    }
+
+    match check {
+        HostCheck::Match => Ok(()),
+        HostCheck::Revoked => anyhow::bail!("{host}:{port} host key is revoked"),
+        HostCheck::Mismatch(old_key_type, old_fingerprint) => Err(HostKeyChanged {
+            host: host.to_string(),
+            port,
+            key_type,
+            old_key_type,
+            old_fingerprint,
+            new_fingerprint: fingerprint(key),
+            key: encode_block(key),
+        }
+        .into()),
+        // 首次连接, 记录主机公钥 (TOFU)
+        HostCheck::NotFound => append_host(&store, host, port, &key_type, &encode_block(key)),
+    }
+}
+
+#[tauri::command]
+pub async fn ssh_trust_host_key(
+    host: String,
+    port: u16,
+    key_type: String,
+    key: String,
+    stat: State<'_, ServerContext>,
+) -> Result<(), Error> {
+    decode_block(&key).map_err(into_essh)?;
+    if key_type.is_empty() || key_type.contains(char::is_whitespace) {
+        return Err(anyhow::anyhow!("invalid key type:{key_type:?}").into());
+    }
+    let store = stat.lock().await.app_path.join(KNOWN_HOSTS_FILE);
+
+    let _guard = KNOWN_HOSTS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let content = std::fs::read_to_string(&store).unwrap_or_default();
+    let lines = replace_host(&content, &host, port, &key_type);
+
+    std::fs::write(&store, lines).map_err(into_essh)?;
+    append_host(&store, &host, port, &key_type, &key).map_err(into_essh)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_A: &[u8] = b"\0\0\0\x0bssh-ed25519key-a";
+    const KEY_B: &[u8] = b"\0\0\0\x0bssh-ed25519key-b";
+
+    fn hashed_pattern(salt: &[u8], entry: &str) -> String {
+        let pkey = PKey::hmac(salt).unwrap();
+        let mut signer = Signer::new(MessageDigest::sha1(), &pkey).unwrap();
+        let hash = signer.sign_oneshot_to_vec(entry.as_bytes()).unwrap();
+        format!("|1|{}|{}", encode_block(salt), encode_block(&hash))
+    }
+
+    #[test]
+    fn parse_markers_and_comments() {
+        let content = "# comment\n\n@revoked *.example ssh-rsa AAAA\nhost1,host2 ssh-ed25519 BBBB extra\nbroken\n";
+        let hosts = parse_known_hosts(content);
+        assert_eq!(hosts.len(), 2);
+        assert_eq!(hosts[0].marker.as_deref(), Some("@revoked"));
+        assert_eq!(hosts[0].patterns, "*.example");
+        assert_eq!(hosts[0].key_type, "ssh-rsa");
+        assert_eq!(hosts[1].marker, None);
+        assert_eq!(hosts[1].patterns, "host1,host2");
+        assert_eq!(hosts[1].key, "BBBB");
+    }
+
+    #[test]
+    fn wildcard_and_negation() {
+        assert!(wildcard_match(b"*.example.com", b"a.example.com"));
+        assert!(wildcard_match(b"host?", b"HOST1"));
+        assert!(!wildcard_match(b"host?", b"host12"));
+        assert!(host_matches(
+            "*.example.com,!bad.example.com",
+            "a.example.com",
+            22
+        ));
+        assert!(!host_matches(
+            "*.example.com,!bad.example.com",
+            "bad.example.com",
+            22
+        ));
+        assert!(host_matches("[db]:2222", "db", 2222));
+        assert!(!host_matches("db", "db", 2222));
+    }
+
+    #[test]
+    fn hashed_host() {
+        let pattern = hashed_pattern(b"0123456789abcdefghij", "[db]:2222");
+        assert!(host_matches(&pattern, "db", 2222));
+        assert!(!host_matches(&pattern, "db", 22));
+        assert!(!host_matches("|1|bad", "db", 2222));
+    }
+
+    #[test]
+    fn check_by_key_type() {
+        let content = format!(
+            "db ssh-ed25519 {}\n@revoked * ssh-ed25519 {}\n",
+            encode_block(KEY_A),
+            encode_block(KEY_B)
+        );
+        assert!(matches!(
+            check_content(&content, "db", 22, "ssh-ed25519", KEY_A),
+            HostCheck::Match
+        ));
+        assert!(matches!(
+            check_content(&content, "db", 22, "ssh-ed25519", KEY_B),
+            HostCheck::Revoked
+        ));
+        assert!(matches!(
+            check_content(&content, "db", 22, "ssh-ed25519", b"other"),
+            HostCheck::Mismatch(t, _) if t == "ssh-ed25519"
+        ));
+        // 只有 ed25519 记录时协商到其他类型也需要确认
+        assert!(matches!(
+            check_content(&content, "db", 22, "ecdsa-sha2-nistp256", b"other"),
+            HostCheck::Mismatch(t, _) if t == "ssh-ed25519"
+        ));
+        assert!(matches!(
+            check_content(&content, "web", 22, "ssh-ed25519", KEY_A),
+            HostCheck::NotFound
+        ));
+    }
+
+    #[test]
+    fn revoked_before_plain_match() {
+        let content = format!(
+            "db ssh-ed25519 {}\n@revoked db ssh-ed25519 {}\n",
+            encode_block(KEY_A),
+            encode_block(KEY_A)
+        );
+        assert!(matches!(
+            check_content(&content, "db", 22, "ssh-ed25519", KEY_A),
+            HostCheck::Revoked
+        ));
+    }
+
+    #[test]
+    fn known_key_types() {
+        let content = "db ssh-ed25519 AAAA\n\
+                       @revoked db ssh-dss BBBB\n\
+                       db,web ssh-rsa CCCC\n\
+                       web ecdsa-sha2-nistp256 DDDD\n\
+                       db ssh-ed25519 EEEE\n";
+        let mut types = Vec::new();
+        content_key_types(content, "db", 22, &mut types);
+        assert_eq!(types, vec!["ssh-ed25519", "ssh-rsa"]);
+        assert_eq!(host_key_algs("ssh-rsa").len(), 3);
+        assert_eq!(host_key_algs("ssh-ed25519"), vec!["ssh-ed25519"]);
+    }
+
+    #[test]
+    fn replace_keeps_markers_and_patterns() {
+        let content = "db ssh-ed25519 AAAA\n\
+                       db ssh-rsa BBBB\n\
+                       @revoked db ssh-ed25519 CCCC\n\
+                       db,web ssh-ed25519 DDDD\n\
+                       [db]:2222 ssh-ed25519 EEEE\n";
+        let lines = replace_host(content, "db", 22, "ssh-ed25519");
+        assert!(!lines.contains("AAAA"));
+        for key in ["BBBB", "CCCC", "DDDD", "EEEE"] {
+            assert!(lines.contains(key), "{key}");
+        }
+    }
+}
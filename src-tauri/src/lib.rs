@@ -1,18 +1,46 @@
+mod broadcast;
 mod crypt;
 mod download;
+mod exec;
+mod known_hosts;
+mod player;
+mod pool;
+mod prompt;
 mod proxy;
+mod record;
 mod server;
+mod session_log;
 mod ssh;
+mod transfer;
+mod tunnel;
 mod upload;
 
+use broadcast::{
+    ssh_broadcast_add, ssh_broadcast_create, ssh_broadcast_delete, ssh_broadcast_list,
+    ssh_broadcast_pause, ssh_broadcast_remove,
+};
 use download::ssh_download;
+use exec::{ssh_exec, ssh_exec_group};
+use known_hosts::ssh_trust_host_key;
+use player::{ssh_play_control, ssh_play_start, ssh_play_stop, PlayerMgr};
+use pool::SessionPool;
+use prompt::{ssh_prompt_cancel, ssh_prompt_reply, PromptMgr};
+use record::{ssh_record_start, ssh_record_stop};
 use server::{
-    ssh_add_server, ssh_config_all, ssh_del_server, ssh_get_servers, ssh_login, ssh_server_detail,
-    ssh_set_config, ssh_update_server, ServerContext, ServerMgr,
+    ssh_add_server, ssh_config_all, ssh_del_proxy, ssh_del_server, ssh_get_proxies,
+    ssh_get_servers, ssh_login, ssh_save_proxy, ssh_server_detail, ssh_set_config,
+    ssh_update_server, ServerContext, ServerMgr,
 };
-use ssh::{ssh_close, ssh_connect, ssh_send, SShMgr};
+use session_log::{ssh_log_start, ssh_log_stop};
+use ssh::{ssh_close, ssh_connect, ssh_duplicate, ssh_send, SShMgr};
 use tauri::Manager;
 use tauri_plugin_window_state::{AppHandleExt, StateFlags, WindowExt};
+use transfer::{
+    ssh_transfer_add, ssh_transfer_cancel, ssh_transfer_clear, ssh_transfer_id, ssh_transfer_list,
+    ssh_transfer_pause, ssh_transfer_remove, ssh_transfer_resume, ssh_transfer_retry,
+    ssh_transfer_set_concurrency, TransferMgr,
+};
+use tunnel::{ssh_tunnel_list, ssh_tunnel_start, ssh_tunnel_stop, TunnelMgr};
 use upload::ssh_upload;
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
@@ -32,6 +60,9 @@ pub fn run() {
         .plugin(tauri_plugin_clipboard_manager::init())
         .invoke_handler(tauri::generate_handler![
             ssh_connect,
+            ssh_duplicate,
+            ssh_exec,
+            ssh_exec_group,
             ssh_send,
             ssh_close,
             ssh_login,
@@ -44,6 +75,38 @@ pub fn run() {
             ssh_download,
             ssh_config_all,
             ssh_set_config,
+            ssh_get_proxies,
+            ssh_save_proxy,
+            ssh_del_proxy,
+            ssh_trust_host_key,
+            ssh_prompt_reply,
+            ssh_prompt_cancel,
+            ssh_tunnel_start,
+            ssh_tunnel_stop,
+            ssh_tunnel_list,
+            ssh_broadcast_create,
+            ssh_broadcast_delete,
+            ssh_broadcast_add,
+            ssh_broadcast_remove,
+            ssh_broadcast_pause,
+            ssh_broadcast_list,
+            ssh_record_start,
+            ssh_record_stop,
+            ssh_log_start,
+            ssh_log_stop,
+            ssh_play_start,
+            ssh_play_control,
+            ssh_play_stop,
+            ssh_transfer_id,
+            ssh_transfer_add,
+            ssh_transfer_list,
+            ssh_transfer_cancel,
+            ssh_transfer_pause,
+            ssh_transfer_resume,
+            ssh_transfer_retry,
+            ssh_transfer_set_concurrency,
+            ssh_transfer_remove,
+            ssh_transfer_clear,
         ])
         .on_window_event(|w, event| {
             if let tauri::WindowEvent::Destroyed = event {
@@ -52,6 +115,11 @@ pub fn run() {
             }
         })
         .manage(SShMgr::default())
+        .manage(PromptMgr::default())
+        .manage(TunnelMgr::default())
+        .manage(SessionPool::default())
+        .manage(PlayerMgr::default())
+        .manage(TransferMgr::default())
         .manage(ServerContext::new(ServerMgr::new()))
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
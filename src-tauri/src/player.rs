@@ -0,0 +1,296 @@
+use crate::ssh::{into_essh, Error, SshMessage, CMD_DATA, CMD_PLAY_END, CMD_RESIZE};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+use tauri::{async_runtime::Mutex, ipc::Channel, AppHandle, Manager, State};
+use tokio::sync::mpsc;
+
+static PLAYER_ID_MGR: AtomicU32 = AtomicU32::new(1);
+
+// seek 时补发的输出按此大小分块发送
+const SEEK_CHUNK: usize = 64 * 1024;
+
+const SPEED_MIN: f64 = 0.1;
+const SPEED_MAX: f64 = 100.0;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum PlayControl {
+    Pause,
+    Resume,
+    // 秒, 为压缩空闲时间后的时间轴
+    Seek { time: f64 },
+    Speed { speed: f64 },
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PlayInfo {
+    id: u32,
+    width: u32,
+    height: u32,
+    duration: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct CastHeader {
+    width: u32,
+    height: u32,
+}
+
+pub type PlayerMgr = Mutex<HashMap<u32, mpsc::Sender<PlayControl>>>;
+
+// (时间, 消息类型, 数据), 数据与终端消息相同
+type Frame = (f64, i32, String);
+
+fn resize_data(cols: u32, rows: u32) -> String {
+    json!({ "cols": cols, "rows": rows }).to_string()
+}
+
+// 读取 asciicast v2 输出和尺寸变化帧, 超过 idle_limit 的空闲时间压缩为 idle_limit
+fn load_cast(content: &str, idle_limit: Option<f64>) -> Result<(CastHeader, Vec<Frame>)> {
+    let mut lines = content.lines().filter(|v| !v.trim().is_empty());
+    let header: CastHeader = serde_json::from_str(lines.next().unwrap_or_default())?;
+
+    let mut frames = Vec::new();
+    let (mut last, mut time) = (0.0, 0.0);
+    for line in lines {
+        let (t, code, data): (f64, String, String) = serde_json::from_str(line)?;
+        let frame = match code.as_str() {
+            "o" => (CMD_DATA, data),
+            "r" => {
+                let Some((cols, rows)) = data.split_once('x') else {
+                    continue;
+                };
+                let (Ok(cols), Ok(rows)) = (cols.parse(), rows.parse()) else {
+                    continue;
+                };
+                (CMD_RESIZE, resize_data(cols, rows))
+            }
+            _ => continue,
+        };
+
+        let gap = (t - last).max(0.0);
+        time += idle_limit.map_or(gap, |v| gap.min(v));
+        last = t;
+        frames.push((time, frame.0, frame.1));
+    }
+
+    Ok((header, frames))
+}
+
+fn send_frame(on_message: &Channel<serde_json::Value>, code: i32, data: String) -> bool {
+    match serde_json::to_value(SshMessage { code, data }) {
+        Ok(v) => on_message.send(v).is_ok(),
+        Err(_) => false,
+    }
+}
+
+// 重置终端和尺寸后按顺序补发帧, 连续的输出合并为不超过 SEEK_CHUNK 的消息
+fn replay(on_message: &Channel<serde_json::Value>, header: &CastHeader, frames: &[Frame]) -> bool {
+    if !send_frame(
+        on_message,
+        CMD_RESIZE,
+        resize_data(header.width, header.height),
+    ) {
+        return false;
+    }
+
+    let mut data = String::from("\x1bc");
+    for (_, code, v) in frames {
+        if *code == CMD_DATA && data.len() + v.len() <= SEEK_CHUNK {
+            data.push_str(v);
+            continue;
+        }
+
+        if !data.is_empty() && !send_frame(on_message, CMD_DATA, std::mem::take(&mut data)) {
+            return false;
+        }
+        match *code {
+            CMD_DATA => data.push_str(v),
+            _ if !send_frame(on_message, *code, v.clone()) => return false,
+            _ => {}
+        }
+    }
+
+    data.is_empty() || send_frame(on_message, CMD_DATA, data)
+}
+
+fn clamp_speed(speed: f64) -> Option<f64> {
+    match speed > 0.0 {
+        true => Some(speed.clamp(SPEED_MIN, SPEED_MAX)),
+        false => None,
+    }
+}
+
+async fn play_loop(
+    id: u32,
+    header: CastHeader,
+    frames: Vec<Frame>,
+    mut speed: f64,
+    mut rx: mpsc::Receiver<PlayControl>,
+    on_message: Channel<serde_json::Value>,
+) {
+    let (mut idx, mut clock, mut paused) = (0, 0.0, false);
+
+    while idx < frames.len() {
+        let wait = match paused {
+            false => Some((frames[idx].0 - clock).max(0.0) / speed),
+            true => None,
+        };
+        // 时间戳异常时停止播放
+        let delay = match wait.map(Duration::try_from_secs_f64) {
+            Some(Ok(v)) => v,
+            Some(Err(_)) => break,
+            None => Duration::ZERO,
+        };
+
+        let started = Instant::now();
+        let cmd = tokio::select! {
+            cmd = rx.recv() => match cmd {
+                Some(v) => v,
+                None => return,
+            },
+            _ = tokio::time::sleep(delay), if wait.is_some() => {
+                let (time, code, data) = &frames[idx];
+                if !send_frame(&on_message, *code, data.clone()) {
+                    return;
+                }
+                clock = *time;
+                idx += 1;
+                continue;
+            }
+        };
+
+        if let Some(v) = wait {
+            let elapsed = started.elapsed().as_secs_f64().min(v);
+            clock += elapsed * speed;
+        }
+
+        match cmd {
+            PlayControl::Pause => paused = true,
+            PlayControl::Resume => paused = false,
+            PlayControl::Speed { speed: v } => speed = clamp_speed(v).unwrap_or(speed),
+            PlayControl::Seek { time } => {
+                let pos = frames.partition_point(|v| v.0 <= time);
+                if !replay(&on_message, &header, &frames[..pos]) {
+                    return;
+                }
+
+                idx = pos;
+                clock = time.max(0.0);
+            }
+        }
+    }
+
+    send_frame(&on_message, CMD_PLAY_END, id.to_string());
+}
+
+#[tauri::command]
+pub async fn ssh_play_start(
+    path: String,
+    speed: Option<f64>,
+    idle_limit: Option<f64>,
+    on_message: Channel<serde_json::Value>,
+    app: AppHandle,
+    player_mgr: State<'_, PlayerMgr>,
+) -> Result<PlayInfo, Error> {
+    let content = tokio::fs::read_to_string(&path).await.map_err(into_essh)?;
+    let (header, frames) = load_cast(&content, idle_limit).map_err(into_essh)?;
+
+    let id = PLAYER_ID_MGR.fetch_add(1, Ordering::Release);
+    let info = PlayInfo {
+        id,
+        width: header.width,
+        height: header.height,
+        duration: frames.last().map_or(0.0, |v| v.0),
+    };
+
+    let speed = speed.and_then(clamp_speed).unwrap_or(1.0);
+    let (tx, rx) = mpsc::channel(10);
+    player_mgr.lock().await.insert(id, tx);
+
+    // 播放结束或前端关闭后从管理器中移除
+    tauri::async_runtime::spawn(async move {
+        play_loop(id, header, frames, speed, rx, on_message).await;
+        app.state::<PlayerMgr>().lock().await.remove(&id);
+    });
+    Ok(info)
+}
+
+#[tauri::command]
+pub async fn ssh_play_control(
+    id: u32,
+    control: PlayControl,
+    player_mgr: State<'_, PlayerMgr>,
+) -> Result<(), Error> {
+    let tx = player_mgr
+        .lock()
+        .await
+        .get(&id)
+        .ok_or(anyhow::anyhow!("player not found:{id}"))?
+        .clone();
+
+    tx.send(control).await.map_err(into_essh)?;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn ssh_play_stop(id: u32, player_mgr: State<'_, PlayerMgr>) -> Result<(), Error> {
+    player_mgr.lock().await.remove(&id);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAST: &str = r#"{"version": 2, "width": 100, "height": 30, "timestamp": 1}
+[0.5, "o", "hello"]
+[1.0, "i", "ls\r"]
+
+[1.5, "r", "120x40"]
+[11.5, "o", "world"]
+[12.0, "r", "bad"]
+"#;
+
+    #[test]
+    fn cast_header_and_frames() {
+        let (header, frames) = load_cast(CAST, None).unwrap();
+        assert_eq!((header.width, header.height), (100, 30));
+        assert_eq!(
+            frames,
+            vec![
+                (0.5, CMD_DATA, String::from("hello")),
+                (1.5, CMD_RESIZE, resize_data(120, 40)),
+                (11.5, CMD_DATA, String::from("world")),
+            ]
+        );
+    }
+
+    #[test]
+    fn cast_idle_limit() {
+        let (_, frames) = load_cast(CAST, Some(2.0)).unwrap();
+        let times: Vec<f64> = frames.iter().map(|v| v.0).collect();
+        assert_eq!(times, vec![0.5, 1.5, 3.5]);
+    }
+
+    #[test]
+    fn speed_range() {
+        assert_eq!(clamp_speed(2.0), Some(2.0));
+        assert_eq!(clamp_speed(1e-12), Some(SPEED_MIN));
+        assert_eq!(clamp_speed(1e300), Some(SPEED_MAX));
+        assert_eq!(clamp_speed(0.0), None);
+        assert_eq!(clamp_speed(f64::NAN), None);
+    }
+
+    #[test]
+    fn cast_invalid() {
+        assert!(load_cast("", None).is_err());
+        assert!(load_cast("{\"width\": 1, \"height\": 1}\n[0.1, \"o\"]", None).is_err());
+    }
+}
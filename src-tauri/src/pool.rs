@@ -0,0 +1,221 @@
+use crate::{prompt::AuthPrompt, server::ConnectInfo, ssh::ssh_create_session};
+use anyhow::Result;
+use async_ssh2_lite::{AsyncSession, TokioTcpStream};
+use std::{
+    collections::HashMap,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tauri::async_runtime::Mutex;
+
+// 引用归零后保留会话的时间
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+static SESSION_GEN_MGR: AtomicU64 = AtomicU64::new(1);
+
+struct PoolEntry {
+    gen: u64,
+    session: AsyncSession<TokioTcpStream>,
+    refs: usize,
+    idle_since: Option<Instant>,
+}
+
+#[derive(Default)]
+pub struct PoolInner {
+    sessions: std::sync::Mutex<HashMap<u32, PoolEntry>>,
+    // 同一服务器串行建立会话, 避免重复登录和重复提示
+    connecting: std::sync::Mutex<HashMap<u32, Arc<Mutex<()>>>>,
+}
+
+// 连接结束 (包括被取消) 后移除串行锁, 仍有其他等待者时保留
+struct Connecting<'a> {
+    pool: &'a PoolInner,
+    id: u32,
+    lock: Arc<Mutex<()>>,
+}
+
+impl Drop for Connecting<'_> {
+    fn drop(&mut self) {
+        let mut l = self
+            .pool
+            .connecting
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        // map 中一份, 自身一份
+        if Arc::strong_count(&self.lock) <= 2 {
+            l.remove(&self.id);
+        }
+    }
+}
+
+pub type SessionPool = Arc<PoolInner>;
+
+// 持有期间会话不会被空闲回收
+pub struct PooledSession {
+    pool: SessionPool,
+    id: u32,
+    gen: u64,
+    session: AsyncSession<TokioTcpStream>,
+}
+
+impl Deref for PooledSession {
+    type Target = AsyncSession<TokioTcpStream>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.session
+    }
+}
+
+impl Clone for PooledSession {
+    fn clone(&self) -> Self {
+        if let Some(v) = self.pool.lock().get_mut(&self.id) {
+            if v.gen == self.gen {
+                v.refs += 1;
+                v.idle_since = None;
+            }
+        }
+
+        Self {
+            pool: self.pool.clone(),
+            id: self.id,
+            gen: self.gen,
+            session: self.session.clone(),
+        }
+    }
+}
+
+impl PooledSession {
+    // 会话已断开, 从池中移除, 已持有的引用不受影响
+    pub fn invalidate(&self) {
+        let mut l = self.pool.lock();
+        if l.get(&self.id).is_some_and(|v| v.gen == self.gen) {
+            l.remove(&self.id);
+        }
+    }
+}
+
+impl Drop for PooledSession {
+    fn drop(&mut self) {
+        let mut l = self.pool.lock();
+        let Some(v) = l.get_mut(&self.id).filter(|v| v.gen == self.gen) else {
+            return;
+        };
+
+        v.refs = v.refs.saturating_sub(1);
+        if v.refs > 0 {
+            return;
+        }
+        v.idle_since = Some(Instant::now());
+        drop(l);
+
+        let (pool, id, gen) = (self.pool.clone(), self.id, self.gen);
+        tauri::async_runtime::spawn(async move {
+            tokio::time::sleep(IDLE_TIMEOUT).await;
+            pool.reap(id, gen).await;
+        });
+    }
+}
+
+impl PoolInner {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u32, PoolEntry>> {
+        self.sessions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    async fn reap(&self, id: u32, gen: u64) {
+        let session = {
+            let mut l = self.lock();
+            let idle = l.get(&id).is_some_and(|v| {
+                v.gen == gen && v.idle_since.is_some_and(|t| t.elapsed() >= IDLE_TIMEOUT)
+            });
+            match idle {
+                true => l.remove(&id).map(|v| v.session),
+                false => None,
+            }
+        };
+
+        if let Some(session) = session {
+            session.disconnect(None, "idle timeout", None).await.ok();
+        }
+    }
+
+    // 取出可用会话并增加引用, 断开的会话直接丢弃
+    async fn take(self: &Arc<Self>, id: u32) -> Option<PooledSession> {
+        let (gen, session) = {
+            let l = self.lock();
+            let v = l.get(&id)?;
+            (v.gen, v.session.clone())
+        };
+
+        if session.keepalive_send().await.is_err() {
+            let mut l = self.lock();
+            if l.get(&id).is_some_and(|v| v.gen == gen) {
+                l.remove(&id);
+            }
+            return None;
+        }
+
+        let mut l = self.lock();
+        let v = l.get_mut(&id).filter(|v| v.gen == gen)?;
+        v.refs += 1;
+        v.idle_since = None;
+
+        Some(PooledSession {
+            pool: self.clone(),
+            id,
+            gen,
+            session,
+        })
+    }
+
+    // 服务器配置变化后不再复用旧会话
+    pub fn forget(&self, id: u32) {
+        self.lock().remove(&id);
+    }
+}
+
+pub async fn session_acquire(
+    pool: &SessionPool,
+    id: u32,
+    info: &ConnectInfo,
+    prompt: &AuthPrompt<'_>,
+) -> Result<PooledSession> {
+    let connecting = Connecting {
+        pool,
+        id,
+        lock: pool
+            .connecting
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(id)
+            .or_default()
+            .clone(),
+    };
+    let _guard = connecting.lock.lock().await;
+
+    if let Some(v) = pool.take(id).await {
+        return Ok(v);
+    }
+
+    let session = ssh_create_session(info, prompt).await?;
+    let gen = SESSION_GEN_MGR.fetch_add(1, Ordering::Release);
+    pool.lock().insert(
+        id,
+        PoolEntry {
+            gen,
+            session: session.clone(),
+            refs: 1,
+            idle_since: None,
+        },
+    );
+
+    Ok(PooledSession {
+        pool: pool.clone(),
+        id,
+        gen,
+        session,
+    })
+}
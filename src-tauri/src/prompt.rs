@@ -0,0 +1,180 @@
+use crate::{
+    server::ServerDetail,
+    ssh::{Error, CMD_PROMPT},
+};
+use async_ssh2_lite::ssh2::{KeyboardInteractivePrompt, Prompt};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tauri::{ipc::Channel, Emitter, State};
+use tokio::{runtime::Handle, sync::oneshot};
+
+pub const ENT_PROMPT: &str = "tauri://SshPromptMessage";
+const PROMPT_TIMEOUT: Duration = Duration::from_secs(120);
+
+static PROMPT_ID_MGR: AtomicU32 = AtomicU32::new(1);
+
+pub type PromptMgr = Arc<Mutex<HashMap<u32, oneshot::Sender<Vec<String>>>>>;
+
+// 提示发送到前端的方式: 终端走 Channel, 文件传输走窗口事件
+#[derive(Clone)]
+pub enum PromptSink {
+    Channel(Channel<serde_json::Value>),
+    Window(tauri::Window),
+}
+
+impl PromptSink {
+    fn send(&self, value: serde_json::Value) -> bool {
+        match self {
+            PromptSink::Channel(c) => c
+                .send(json!({
+                    "code": CMD_PROMPT,
+                    "data": value,
+                }))
+                .is_ok(),
+            PromptSink::Window(w) => w.emit(ENT_PROMPT, value).is_ok(),
+        }
+    }
+}
+
+pub struct AuthPrompt<'a> {
+    pub mgr: &'a PromptMgr,
+    pub sink: PromptSink,
+}
+
+// 需要移动到阻塞线程中使用, 不借用调用方的数据
+pub struct KbdPrompter {
+    server_name: String,
+    password: String,
+    mgr: PromptMgr,
+    sink: PromptSink,
+    handle: Handle,
+    password_used: bool,
+    pub cancelled: bool,
+}
+
+impl KbdPrompter {
+    pub fn new(server: &ServerDetail, prompt: &AuthPrompt<'_>) -> Self {
+        Self {
+            server_name: server.name.clone(),
+            password: server.password.clone(),
+            mgr: prompt.mgr.clone(),
+            sink: prompt.sink.clone(),
+            handle: Handle::current(),
+            password_used: false,
+            cancelled: false,
+        }
+    }
+
+    fn ask(&mut self, username: &str, instructions: &str, prompts: &[&Prompt]) -> Vec<String> {
+        let id = PROMPT_ID_MGR.fetch_add(1, Ordering::Release);
+        let (tx, rx) = oneshot::channel();
+        self.lock().insert(id, tx);
+
+        let value = json!({
+            "id": id,
+            "server": self.server_name,
+            "username": username,
+            "instructions": instructions,
+            "prompts": prompts
+                .iter()
+                .map(|p| json!({ "text": p.text, "echo": p.echo }))
+                .collect::<Vec<_>>(),
+        });
+
+        // libssh2 回调是同步的, 在 spawn_blocking 线程中等待前端 ssh_prompt_reply,
+        // 前端取消或超时时 tx 被丢弃
+        let answers = match self.sink.send(value) {
+            true => tokio::task::block_in_place(|| {
+                self.handle
+                    .block_on(tokio::time::timeout(PROMPT_TIMEOUT, rx))
+            })
+            .ok()
+            .and_then(Result::ok),
+            false => None,
+        };
+
+        self.lock().remove(&id);
+        match answers {
+            Some(v) => v,
+            None => {
+                self.cancelled = true;
+                Vec::new()
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u32, oneshot::Sender<Vec<String>>>> {
+        self.mgr.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl KeyboardInteractivePrompt for KbdPrompter {
+    fn prompt<'b>(
+        &mut self,
+        username: &str,
+        instructions: &str,
+        prompts: &[Prompt<'b>],
+    ) -> Vec<String> {
+        let mut answers: Vec<Option<String>> = vec![None; prompts.len()];
+
+        // 静态密码提示使用保存的密码, 只自动应答一次, 失败后交给用户输入
+        if self.cancelled {
+            return vec![String::new(); prompts.len()];
+        }
+
+        if !self.password_used && !self.password.is_empty() {
+            for (i, p) in prompts.iter().enumerate() {
+                if !p.echo && p.text.to_lowercase().contains("password") {
+                    answers[i] = Some(self.password.clone());
+                    self.password_used = true;
+                }
+            }
+        }
+
+        let pending: Vec<&Prompt> = prompts
+            .iter()
+            .zip(answers.iter())
+            .filter(|(_, a)| a.is_none())
+            .map(|(p, _)| p)
+            .collect();
+
+        if !pending.is_empty() {
+            let mut replies = self.ask(username, instructions, &pending).into_iter();
+            for a in answers.iter_mut().filter(|a| a.is_none()) {
+                *a = Some(replies.next().unwrap_or_default());
+            }
+        }
+
+        answers.into_iter().map(Option::unwrap_or_default).collect()
+    }
+}
+
+#[tauri::command]
+pub async fn ssh_prompt_reply(
+    id: u32,
+    answers: Vec<String>,
+    mgr: State<'_, PromptMgr>,
+) -> Result<(), Error> {
+    let tx = mgr
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(&id)
+        .ok_or(anyhow::anyhow!("prompt not found:{id}"))?;
+
+    tx.send(answers).ok();
+    Ok(())
+}
+
+// 终端关闭时取消等待中的提示, 认证随即失败
+#[tauri::command]
+pub async fn ssh_prompt_cancel(id: u32, mgr: State<'_, PromptMgr>) -> Result<(), Error> {
+    mgr.lock().unwrap_or_else(|e| e.into_inner()).remove(&id);
+    Ok(())
+}
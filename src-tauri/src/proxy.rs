@@ -1,18 +1,426 @@
-use crate::server::{Config, ServerDetail};
+use crate::server::{ProxyProfile, ProxyScheme, ServerDetail};
 use anyhow::Result;
-use async_ssh2_lite::TokioTcpStream;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-
-pub async fn ssh_proxy_connect(server: &ServerDetail, cfg: &Config) -> Result<TokioTcpStream> {
-    let mut stream = TokioTcpStream::connect(&cfg.proxy_addr).await?;
-    let req = format!(
-        "CONNECT {}:{} HTTP/1.1\r\nHost: {0}\r\n\r\n",
-        server.host, server.port
-    );
+use async_ssh2_lite::{AsyncSession, TokioTcpStream};
+use openssl::base64::encode_block;
+use std::net::IpAddr;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+const HTTP_HEAD_MAX: usize = 8192;
+pub const SOCKS5_VER: u8 = 0x05;
+pub const SOCKS5_AUTH_NONE: u8 = 0x00;
+const SOCKS5_AUTH_PASSWORD: u8 = 0x02;
+pub const SOCKS5_AUTH_REJECT: u8 = 0xFF;
+pub const SOCKS5_CMD_CONNECT: u8 = 0x01;
+pub const SOCKS5_ATYP_IPV4: u8 = 0x01;
+pub const SOCKS5_ATYP_DOMAIN: u8 = 0x03;
+pub const SOCKS5_ATYP_IPV6: u8 = 0x04;
+
+struct HttpResponse {
+    status: u16,
+    reason: String,
+    headers: Vec<(String, String)>,
+}
+
+pub async fn ssh_proxy_connect(
+    server: &ServerDetail,
+    proxy: &ProxyProfile,
+) -> Result<TokioTcpStream> {
+    match proxy.scheme {
+        ProxyScheme::Http => http_connect(server, proxy).await,
+        ProxyScheme::Socks5 => socks5_connect(&server.host, server.port, proxy).await,
+    }
+}
+
+async fn http_connect(server: &ServerDetail, proxy: &ProxyProfile) -> Result<TokioTcpStream> {
+    let mut stream = TokioTcpStream::connect(&proxy.addr).await?;
+    let target = match server.host.contains(':') {
+        true => format!("[{}]:{}", server.host, server.port),
+        false => format!("{}:{}", server.host, server.port),
+    };
+
+    let mut req = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+    if !proxy.username.is_empty() {
+        let token = encode_block(format!("{}:{}", proxy.username, proxy.password).as_bytes());
+        req.push_str(&format!("Proxy-Authorization: Basic {token}\r\n"));
+    }
+    req.push_str("\r\n");
     stream.write_all(req.as_bytes()).await?;
 
-    let mut buf = [0; 1024];
-    let _ = stream.read(&mut buf).await?;
+    let head = read_http_head(&mut stream).await?;
+    let HttpResponse {
+        status,
+        reason,
+        headers,
+    } = parse_http_head(&head)?;
+
+    match status {
+        200..=299 => Ok(stream),
+        407 => {
+            let auth = headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case("Proxy-Authenticate"))
+                .map(|(_, v)| v.as_str())
+                .unwrap_or_default();
+            anyhow::bail!("proxy CONNECT failed: {status} {reason} {auth}")
+        }
+        _ => anyhow::bail!("proxy CONNECT failed: {status} {reason}"),
+    }
+}
+
+// 只消费响应头, 其后的数据属于 ssh 握手, 必须留在 socket 中
+async fn read_http_head(stream: &mut TokioTcpStream) -> Result<String> {
+    let mut buf = vec![0u8; HTTP_HEAD_MAX];
+    let mut head: Vec<u8> = Vec::with_capacity(1024);
+
+    loop {
+        let n = stream.peek(&mut buf).await?;
+        if n == 0 {
+            anyhow::bail!("proxy closed connection");
+        }
+
+        let start = head.len().saturating_sub(3);
+        head.extend_from_slice(&buf[..n]);
+        let end = head[start..]
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .map(|i| start + i + 4);
+
+        let take = match end {
+            Some(end) => n - (head.len() - end),
+            None => n,
+        };
+        head.truncate(head.len() - n + take);
+        stream.read_exact(&mut buf[..take]).await?;
+
+        if end.is_some() {
+            break;
+        }
+        if head.len() >= HTTP_HEAD_MAX {
+            anyhow::bail!("proxy response header too large");
+        }
+    }
+
+    Ok(String::from_utf8_lossy(&head).to_string())
+}
+
+fn parse_http_head(head: &str) -> Result<HttpResponse> {
+    let mut lines = head.split("\r\n");
+    let status_line = lines.next().unwrap_or_default();
+
+    let mut parts = status_line.splitn(3, ' ');
+    let version = parts.next().unwrap_or_default();
+    if !version.starts_with("HTTP/1.") {
+        anyhow::bail!("invalid proxy response:{status_line}");
+    }
+    let status = parts
+        .next()
+        .and_then(|v| v.parse::<u16>().ok())
+        .ok_or(anyhow::anyhow!("invalid proxy response:{status_line}"))?;
+    let reason = parts.next().unwrap_or_default().to_string();
+
+    let headers = lines
+        .filter_map(|l| l.split_once(':'))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect();
+
+    Ok(HttpResponse {
+        status,
+        reason,
+        headers,
+    })
+}
+
+// RFC 1928, 目标地址为域名时交给代理解析
+async fn socks5_connect(host: &str, port: u16, proxy: &ProxyProfile) -> Result<TokioTcpStream> {
+    let mut stream = TokioTcpStream::connect(&proxy.addr).await?;
+
+    let use_auth = !proxy.username.is_empty();
+    match use_auth {
+        true => {
+            stream
+                .write_all(&[SOCKS5_VER, 2, SOCKS5_AUTH_NONE, SOCKS5_AUTH_PASSWORD])
+                .await?
+        }
+        false => stream.write_all(&[SOCKS5_VER, 1, SOCKS5_AUTH_NONE]).await?,
+    }
+
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS5_VER {
+        anyhow::bail!("socks5 invalid version:{}", reply[0]);
+    }
+
+    match reply[1] {
+        SOCKS5_AUTH_NONE => {}
+        SOCKS5_AUTH_PASSWORD if use_auth => socks5_auth(&mut stream, proxy).await?,
+        SOCKS5_AUTH_REJECT => anyhow::bail!("socks5 no acceptable auth method"),
+        m => anyhow::bail!("socks5 unsupported auth method:{m}"),
+    }
+
+    let mut req = vec![SOCKS5_VER, SOCKS5_CMD_CONNECT, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            req.push(SOCKS5_ATYP_IPV4);
+            req.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            req.push(SOCKS5_ATYP_IPV6);
+            req.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let len = u8::try_from(host.len())?;
+            req.push(SOCKS5_ATYP_DOMAIN);
+            req.push(len);
+            req.extend_from_slice(host.as_bytes());
+        }
+    }
+    req.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&req).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        anyhow::bail!(
+            "socks5 connect {host}:{port} failed: {}",
+            socks5_error(reply[1])
+        );
+    }
+
+    // 跳过 BND.ADDR 和 BND.PORT
+    let addr_len = match reply[3] {
+        SOCKS5_ATYP_IPV4 => 4,
+        SOCKS5_ATYP_IPV6 => 16,
+        SOCKS5_ATYP_DOMAIN => stream.read_u8().await? as usize,
+        v => anyhow::bail!("socks5 invalid address type:{v}"),
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
 
     Ok(stream)
 }
+
+// RFC 1929 用户名/密码认证
+async fn socks5_auth(stream: &mut TokioTcpStream, proxy: &ProxyProfile) -> Result<()> {
+    let username = proxy.username.as_bytes();
+    let password = proxy.password.as_bytes();
+
+    let mut req = vec![0x01, u8::try_from(username.len())?];
+    req.extend_from_slice(username);
+    req.push(u8::try_from(password.len())?);
+    req.extend_from_slice(password);
+    stream.write_all(&req).await?;
+
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        anyhow::bail!("socks5 authentication failed");
+    }
+    Ok(())
+}
+
+fn socks5_error(code: u8) -> &'static str {
+    match code {
+        0x01 => "general failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+// libssh2 需要真实的 socket, 通过本地回环连接承载上一跳的 direct-tcpip 通道
+pub async fn ssh_jump_connect(
+    session: &AsyncSession<TokioTcpStream>,
+    server: &ServerDetail,
+) -> Result<TokioTcpStream> {
+    let mut channel = session
+        .channel_direct_tcpip(&server.host, server.port, None)
+        .await?;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let (stream, (mut local, peer)) =
+        tokio::try_join!(TokioTcpStream::connect(addr), listener.accept())?;
+
+    if peer != stream.local_addr()? {
+        anyhow::bail!("unexpected jump peer:{peer}");
+    }
+
+    stream.set_nodelay(true)?;
+    local.set_nodelay(true)?;
+
+    // 跳板通道断开后关闭本地端, 外层会话随即读到 EOF 并结束
+    tauri::async_runtime::spawn(async move {
+        if let Err(_e) = tokio::io::copy_bidirectional(&mut local, &mut channel).await {
+            // log
+        }
+        local.shutdown().await.ok();
+        channel.close().await.ok();
+    });
+
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 最小化的 SOCKS5 服务端, 返回客户端请求的目标地址, 之后回显数据
+    async fn socks5_stand_in(listener: TcpListener, auth: Option<(&str, &str)>) -> String {
+        let (mut s, _) = listener.accept().await.unwrap();
+
+        let mut head = [0u8; 2];
+        s.read_exact(&mut head).await.unwrap();
+        let mut methods = vec![0u8; head[1] as usize];
+        s.read_exact(&mut methods).await.unwrap();
+
+        match auth {
+            Some((user, pass)) => {
+                assert!(methods.contains(&SOCKS5_AUTH_PASSWORD));
+                s.write_all(&[SOCKS5_VER, SOCKS5_AUTH_PASSWORD])
+                    .await
+                    .unwrap();
+
+                let mut buf = vec![0u8; 2];
+                s.read_exact(&mut buf).await.unwrap();
+                let mut u = vec![0u8; buf[1] as usize];
+                s.read_exact(&mut u).await.unwrap();
+                let mut p = vec![0u8; s.read_u8().await.unwrap() as usize];
+                s.read_exact(&mut p).await.unwrap();
+
+                let ok = u == user.as_bytes() && p == pass.as_bytes();
+                s.write_all(&[0x01, if ok { 0 } else { 1 }]).await.unwrap();
+                if !ok {
+                    return String::new();
+                }
+            }
+            None => s.write_all(&[SOCKS5_VER, SOCKS5_AUTH_NONE]).await.unwrap(),
+        }
+
+        let mut req = [0u8; 4];
+        s.read_exact(&mut req).await.unwrap();
+        assert_eq!(req[1], SOCKS5_CMD_CONNECT);
+        assert_eq!(req[3], SOCKS5_ATYP_DOMAIN);
+        let mut host = vec![0u8; s.read_u8().await.unwrap() as usize];
+        s.read_exact(&mut host).await.unwrap();
+        let port = s.read_u16().await.unwrap();
+
+        s.write_all(&[SOCKS5_VER, 0, 0, SOCKS5_ATYP_IPV4, 127, 0, 0, 1, 0, 0])
+            .await
+            .unwrap();
+
+        let mut buf = [0u8; 4];
+        s.read_exact(&mut buf).await.unwrap();
+        s.write_all(&buf).await.unwrap();
+
+        format!("{}:{port}", String::from_utf8(host).unwrap())
+    }
+
+    async fn run(
+        auth: Option<(&'static str, &'static str)>,
+        proxy: ProxyProfile,
+    ) -> Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy = ProxyProfile {
+            scheme: ProxyScheme::Socks5,
+            addr: listener.local_addr()?.to_string(),
+            ..proxy
+        };
+        let server = tokio::spawn(socks5_stand_in(listener, auth));
+
+        let mut stream = socks5_connect("internal.example", 2222, &proxy).await?;
+        stream.write_all(b"ping").await?;
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"ping");
+
+        Ok(server.await?)
+    }
+
+    async fn http_stand_in(listener: TcpListener, reply: &'static [u8]) -> String {
+        let (mut s, _) = listener.accept().await.unwrap();
+        let mut req = Vec::new();
+        while !req.ends_with(b"\r\n\r\n") {
+            req.push(s.read_u8().await.unwrap());
+        }
+        s.write_all(reply).await.unwrap();
+        String::from_utf8(req).unwrap()
+    }
+
+    async fn run_http(reply: &'static [u8]) -> (Result<TokioTcpStream>, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = ProxyProfile {
+            addr: listener.local_addr().unwrap().to_string(),
+            username: String::from("user"),
+            password: String::from("secret"),
+            ..Default::default()
+        };
+        let server = ServerDetail {
+            host: String::from("internal.example"),
+            port: 22,
+            ..Default::default()
+        };
+        let stand_in = tokio::spawn(http_stand_in(listener, reply));
+        let ret = http_connect(&server, &proxy).await;
+        (ret, stand_in.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn http_connect_keeps_trailing_bytes() {
+        let reply = b"HTTP/1.1 200 Connection established\r\nVia: x\r\n\r\nSSH-2.0-test\r\n";
+        let (ret, req) = run_http(reply).await;
+        assert!(req.starts_with("CONNECT internal.example:22 HTTP/1.1\r\n"));
+        assert!(req.contains("Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n"));
+
+        let mut stream = ret.unwrap();
+        let mut banner = String::new();
+        stream.read_to_string(&mut banner).await.unwrap();
+        assert_eq!(banner, "SSH-2.0-test\r\n");
+    }
+
+    #[tokio::test]
+    async fn http_connect_reports_status() {
+        let reply = b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"x\"\r\n\r\n";
+        let err = run_http(reply).await.0.unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("407 Proxy Authentication Required"));
+
+        let err = run_http(b"HTTP/1.0 502 Bad Gateway\r\n\r\n")
+            .await
+            .0
+            .unwrap_err();
+        assert!(err.to_string().contains("502 Bad Gateway"));
+    }
+
+    #[tokio::test]
+    async fn socks5_no_auth_resolves_remotely() {
+        let target = run(None, ProxyProfile::default()).await.unwrap();
+        assert_eq!(target, "internal.example:2222");
+    }
+
+    #[tokio::test]
+    async fn socks5_password_auth() {
+        let proxy = ProxyProfile {
+            username: String::from("user"),
+            password: String::from("secret"),
+            ..Default::default()
+        };
+        let target = run(Some(("user", "secret")), proxy.clone()).await.unwrap();
+        assert_eq!(target, "internal.example:2222");
+
+        let bad = ProxyProfile {
+            password: String::from("wrong"),
+            ..proxy
+        };
+        let err = run(Some(("user", "secret")), bad).await.unwrap_err();
+        assert!(err.to_string().contains("authentication failed"));
+    }
+}
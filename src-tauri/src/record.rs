@@ -0,0 +1,198 @@
+use crate::{
+    server::{ConnectInfo, ServerContext},
+    ssh::{into_essh, Error, SShMgr, TerminalSize},
+};
+use anyhow::Result;
+use serde_json::json;
+use std::{
+    fs::File,
+    io::Write,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use tauri::State;
+
+pub const RECORD_DIR: &str = "recordings";
+
+// asciicast v2: 首行为头部, 之后每行一个 [time, code, data] 事件
+pub struct Recorder {
+    file: File,
+    start: Instant,
+    input: bool,
+}
+
+pub type SharedRecorder = Arc<Mutex<Option<Recorder>>>;
+
+impl Recorder {
+    pub fn create(
+        path: &Path,
+        size: Option<TerminalSize>,
+        title: &str,
+        input: bool,
+    ) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let (width, height) = size.map_or((80, 24), |v| (v.cols, v.rows));
+        let header = json!({
+            "version": 2,
+            "width": width,
+            "height": height,
+            "timestamp": chrono::Utc::now().timestamp(),
+            "title": title,
+            "env": { "TERM": "xterm" },
+        });
+
+        let mut file = File::create(path)?;
+        writeln!(file, "{header}")?;
+
+        Ok(Self {
+            file,
+            start: Instant::now(),
+            input,
+        })
+    }
+
+    fn event(&mut self, code: &str, data: &str) {
+        let time = self.start.elapsed().as_secs_f64();
+        writeln!(self.file, "{}", json!([time, code, data])).ok();
+    }
+
+    pub fn output(&mut self, data: &str) {
+        self.event("o", data);
+    }
+
+    pub fn input(&mut self, data: &str) {
+        if self.input {
+            self.event("i", data);
+        }
+    }
+
+    pub fn resize(&mut self, size: TerminalSize) {
+        self.event("r", &format!("{}x{}", size.cols, size.rows));
+    }
+}
+
+pub fn record_with(recorder: &SharedRecorder, f: impl FnOnce(&mut Recorder)) {
+    if let Some(v) = recorder.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        f(v);
+    }
+}
+
+// 文件名中不能出现路径分隔符等字符
+pub fn safe_file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            _ => c,
+        })
+        .collect()
+}
+
+fn record_path(app_path: &Path, server_name: &str, id: u32) -> PathBuf {
+    let time = chrono::Local::now().format("%Y%m%d-%H%M%S");
+    let name = format!("{}-{time}-{id}.cast", safe_file_name(server_name));
+    app_path.join(RECORD_DIR).join(name)
+}
+
+// 前端只能指定文件名, 结果总是在程序目录的 recordings 下,
+// 去掉 .. 和根路径等部分, 为空时使用默认名称
+fn named_record_path(app_path: &Path, name: &str) -> Option<PathBuf> {
+    let rel: PathBuf = Path::new(name)
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(v) => Some(v),
+            _ => None,
+        })
+        .collect();
+    match rel.as_os_str().is_empty() {
+        true => None,
+        false => Some(app_path.join(RECORD_DIR).join(rel)),
+    }
+}
+
+// 服务器设置了总是录制时, 新终端自动开始录制
+pub fn auto_recorder(info: &ConnectInfo, id: u32, size: Option<TerminalSize>) -> SharedRecorder {
+    let server = &info.server;
+    let recorder = match server.always_record {
+        true => {
+            let path = record_path(&info.app_path, &server.name, id);
+            Recorder::create(&path, size, &server.name, server.record_input).ok()
+        }
+        false => None,
+    };
+    Arc::new(Mutex::new(recorder))
+}
+
+#[tauri::command]
+pub async fn ssh_record_start(
+    id: u32,
+    name: Option<String>,
+    input: Option<bool>,
+    stat: State<'_, SShMgr>,
+    svr_ctx: State<'_, ServerContext>,
+) -> Result<String, Error> {
+    let ctx = stat
+        .lock()
+        .await
+        .contexts
+        .get(&id)
+        .ok_or(anyhow::anyhow!("ssh context not found"))?
+        .clone();
+
+    let (server_id, size, recorder) = {
+        let l = ctx.lock().await;
+        (l.server_id, l.size, l.recorder.clone())
+    };
+
+    let lsm = svr_ctx.lock().await;
+    let server = lsm
+        .servers
+        .get(&server_id)
+        .ok_or(anyhow::anyhow!("server not found"))?;
+    let path = name
+        .and_then(|v| named_record_path(&lsm.app_path, &v))
+        .unwrap_or_else(|| record_path(&lsm.app_path, &server.name, id));
+    let input = input.unwrap_or(server.record_input);
+    let title = server.name.clone();
+    drop(lsm);
+
+    let v = Recorder::create(&path, size, &title, input).map_err(into_essh)?;
+    *recorder.lock().unwrap_or_else(|e| e.into_inner()) = Some(v);
+
+    Ok(path.to_string_lossy().to_string())
+}
+
+#[tauri::command]
+pub async fn ssh_record_stop(id: u32, stat: State<'_, SShMgr>) -> Result<(), Error> {
+    let ctx = stat.lock().await.contexts.get(&id).cloned();
+    if let Some(ctx) = ctx {
+        let recorder = ctx.lock().await.recorder.clone();
+        recorder.lock().unwrap_or_else(|e| e.into_inner()).take();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_path_stays_in_record_dir() {
+        let app = Path::new("/app");
+        let dir = app.join(RECORD_DIR);
+        assert_eq!(named_record_path(app, "a.cast"), Some(dir.join("a.cast")));
+        assert_eq!(
+            named_record_path(app, "../../x.cast"),
+            Some(dir.join("x.cast"))
+        );
+        assert_eq!(
+            named_record_path(app, "/etc/x.cast"),
+            Some(dir.join("etc/x.cast"))
+        );
+        assert_eq!(named_record_path(app, ".."), None);
+        assert_eq!(named_record_path(app, ""), None);
+    }
+}
@@ -1,10 +1,12 @@
 use crate::{
     crypt::{load_server, save_server, verify_password},
+    pool::SessionPool,
     ssh::{into_essh, Error},
+    tunnel::{tunnel_autostart, TunnelMgr, TunnelSpec},
 };
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use std::{collections::BTreeMap, env, path::PathBuf};
+use std::{collections::BTreeMap, env, path::PathBuf, time::Duration};
 use tauri::{async_runtime::Mutex, State};
 
 const ID_CFG_LOCAL: u32 = 1;
@@ -16,8 +18,11 @@ const ID_CFG_F_NAME: u32 = 6;
 const ID_CFG_F_GRPS: u32 = 7;
 const ID_CFG_S_DGRP: u32 = 8;
 const ID_CFG_S_VALS: u32 = 9;
+const ID_CFG_RECONN: u32 = 10;
+const ID_CFG_SLOG: u32 = 11;
 
 const SERVER_FILE: &str = "servers.json";
+pub const DEFAULT_PROXY: &str = "default";
 const CONFIG_FILE: &str = "config.json";
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
@@ -26,6 +31,16 @@ pub struct ServerItem {
     name: String,
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMode {
+    // 有证书路径时使用证书, 否则使用密码
+    #[default]
+    Auto,
+    // 优先使用 ssh-agent 中的身份, 失败后回退到证书或密码
+    Agent,
+}
+
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct ServerDetail {
     pub name: String,
@@ -37,6 +52,22 @@ pub struct ServerDetail {
     pub cert_pass: String,
     pub cert_path: String,
     pub use_proxy: bool,
+    // 代理配置名称, 为空且 use_proxy 时使用 default 配置
+    #[serde(default)]
+    pub proxy: String,
+    #[serde(default)]
+    pub auth_mode: AuthMode,
+    // 跳板机链, 按顺序保存其他服务器的 id
+    #[serde(default)]
+    pub jump_hosts: Vec<String>,
+    // 保存的端口转发
+    #[serde(default)]
+    pub tunnels: Vec<TunnelSpec>,
+    // 新终端自动录制为 asciicast 文件
+    #[serde(default)]
+    pub always_record: bool,
+    #[serde(default)]
+    pub record_input: bool,
 }
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
@@ -45,10 +76,132 @@ pub struct ServerGroup {
     servers: Vec<ServerItem>,
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyScheme {
+    #[default]
+    Http,
+    Socks5,
+}
+
+// 旧版本的全局代理认证信息, 仅用于迁移
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ProxyAuth {
+    pub username: String,
+    pub password: String,
+}
+
+// 代理配置, 随服务器列表加密保存
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ProxyProfile {
+    pub name: String,
+    #[serde(default)]
+    pub scheme: ProxyScheme,
+    pub addr: String,
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub password: String,
+}
+
+// 终端断线自动重连策略, 指数退避
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReconnectPolicy {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "ReconnectPolicy::default_max_attempts")]
+    pub max_attempts: u32,
+    #[serde(default = "ReconnectPolicy::default_initial_delay")]
+    pub initial_delay: u64,
+    #[serde(default = "ReconnectPolicy::default_max_delay")]
+    pub max_delay: u64,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_attempts: Self::default_max_attempts(),
+            initial_delay: Self::default_initial_delay(),
+            max_delay: Self::default_max_delay(),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    fn default_max_attempts() -> u32 {
+        5
+    }
+    fn default_initial_delay() -> u64 {
+        1000
+    }
+    fn default_max_delay() -> u64 {
+        30000
+    }
+
+    // 第 attempt 次 (从1开始) 重连前的等待时间
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 1u64 << attempt.saturating_sub(1).min(16);
+        let ms = self
+            .initial_delay
+            .saturating_mul(factor)
+            .min(self.max_delay);
+        Duration::from_millis(ms)
+    }
+}
+
+// 会话输出写入文本日志, 按大小轮转
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionLogConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "SessionLogConfig::default_pattern")]
+    pub pattern: String,
+    #[serde(default = "SessionLogConfig::default_strip_ansi")]
+    pub strip_ansi: bool,
+    #[serde(default)]
+    pub timestamp: bool,
+    // 字节, 0 表示不轮转
+    #[serde(default = "SessionLogConfig::default_max_size")]
+    pub max_size: u64,
+    #[serde(default = "SessionLogConfig::default_max_files")]
+    pub max_files: u32,
+}
+
+impl Default for SessionLogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            pattern: Self::default_pattern(),
+            strip_ansi: Self::default_strip_ansi(),
+            timestamp: false,
+            max_size: Self::default_max_size(),
+            max_files: Self::default_max_files(),
+        }
+    }
+}
+
+impl SessionLogConfig {
+    fn default_pattern() -> String {
+        String::from("{server}-{date}-{time}-{id}.log")
+    }
+    fn default_strip_ansi() -> bool {
+        true
+    }
+    fn default_max_size() -> u64 {
+        10 * 1024 * 1024
+    }
+    fn default_max_files() -> u32 {
+        5
+    }
+}
+
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct ConfigValues {
     #[serde(default)]
     pub proxy_addr: String,
+    #[serde(default)]
+    pub proxy_scheme: ProxyScheme,
     #[serde(default = "Config::default_font_name")]
     pub font_name: String,
 }
@@ -57,6 +210,8 @@ pub struct ConfigValues {
 pub struct Config {
     #[serde(default)]
     pub proxy_addr: String,
+    #[serde(default)]
+    pub proxy_scheme: ProxyScheme,
     #[serde(default = "Config::default_font_name")]
     pub font_name: String,
     #[serde(default)]
@@ -75,6 +230,10 @@ pub struct Config {
     pub remote_grps: Vec<String>,
     #[serde(default)]
     pub file_grps: Vec<String>,
+    #[serde(default)]
+    pub reconnect: ReconnectPolicy,
+    #[serde(default)]
+    pub session_log: SessionLogConfig,
 }
 
 impl Config {
@@ -95,6 +254,15 @@ impl Config {
     }
 }
 
+#[derive(Clone, Debug)]
+pub struct ConnectInfo {
+    pub server: ServerDetail,
+    pub jumps: Vec<ServerDetail>,
+    // 代理只作用于第一跳
+    pub proxy: Option<ProxyProfile>,
+    pub app_path: PathBuf,
+}
+
 #[derive(Default, Serialize, Deserialize)]
 pub struct ServerMgr {
     #[serde(skip)]
@@ -106,6 +274,10 @@ pub struct ServerMgr {
     #[serde(skip)]
     pub data_key: Vec<u8>,
     pub servers: BTreeMap<u32, ServerDetail>,
+    #[serde(default)]
+    pub proxies: BTreeMap<String, ProxyProfile>,
+    #[serde(default, skip_serializing)]
+    pub proxy_auth: ProxyAuth,
 }
 
 impl ServerMgr {
@@ -124,6 +296,89 @@ impl ServerMgr {
         }
     }
 
+    pub fn connect_info(&self, id: u32) -> Result<ConnectInfo> {
+        let server = self
+            .servers
+            .get(&id)
+            .ok_or(anyhow::anyhow!("server not found"))?
+            .clone();
+
+        let mut jumps: Vec<ServerDetail> = Vec::with_capacity(server.jump_hosts.len());
+        let mut visited = vec![id];
+        for v in server.jump_hosts.iter() {
+            let key = v.parse::<u32>()?;
+            if visited.contains(&key) {
+                anyhow::bail!("jump host loop:{v}");
+            }
+            visited.push(key);
+
+            let hop = self
+                .servers
+                .get(&key)
+                .ok_or(anyhow::anyhow!("jump host not found:{v}"))?;
+            jumps.push(hop.clone());
+        }
+
+        let first = jumps.first().unwrap_or(&server);
+        let proxy = match (first.proxy.as_str(), first.use_proxy) {
+            ("", true) => Some(
+                self.proxies
+                    .get(DEFAULT_PROXY)
+                    .ok_or(anyhow::anyhow!("proxy not found:{DEFAULT_PROXY}"))?
+                    .clone(),
+            ),
+            (name, true) => Some(
+                self.proxies
+                    .get(name)
+                    .ok_or(anyhow::anyhow!("proxy not found:{name}"))?
+                    .clone(),
+            ),
+            (_, false) => None,
+        };
+
+        Ok(ConnectInfo {
+            server,
+            jumps,
+            proxy,
+            app_path: self.app_path.clone(),
+        })
+    }
+
+    // 未指定代理名称但勾选了代理的服务器使用 default 配置
+    fn proxy_in_use(&self, name: &str) -> Result<()> {
+        let used: Vec<&str> = self
+            .servers
+            .values()
+            .filter(|v| {
+                v.proxy == name || (name == DEFAULT_PROXY && v.use_proxy && v.proxy.is_empty())
+            })
+            .map(|v| v.name.as_str())
+            .collect();
+        if !used.is_empty() {
+            anyhow::bail!("proxy {name} in use:{}", used.join(","));
+        }
+        Ok(())
+    }
+
+    // 旧版本的全局代理迁移为 default 代理配置
+    fn migrate_proxy(&mut self, auth: ProxyAuth) -> bool {
+        if self.config.proxy_addr.is_empty() {
+            return false;
+        }
+
+        let addr = std::mem::take(&mut self.config.proxy_addr);
+        self.proxies
+            .entry(DEFAULT_PROXY.to_string())
+            .or_insert_with(|| ProxyProfile {
+                name: DEFAULT_PROXY.to_string(),
+                scheme: self.config.proxy_scheme,
+                addr,
+                username: auth.username,
+                password: auth.password,
+            });
+        true
+    }
+
     pub fn save(&mut self) -> Result<()> {
         let file_name = self.app_path.join(SERVER_FILE);
         let servers = serde_json::to_string(self)?;
@@ -144,7 +399,9 @@ pub type ServerContext = Mutex<ServerMgr>;
 pub async fn ssh_login(
     name: String,
     password: String,
+    wnd: tauri::Window,
     stat: State<'_, ServerContext>,
+    tunnel_mgr: State<'_, TunnelMgr>,
 ) -> Result<(), Error> {
     let mut server_mgr = stat.lock().await;
 
@@ -155,10 +412,32 @@ pub async fn ssh_login(
         let servers = load_server(&file_name, &user_key, &data_key)?;
         let mgr: ServerMgr = serde_json::from_str(&servers).map_err(into_essh)?;
         server_mgr.servers = mgr.servers;
+        server_mgr.proxies = mgr.proxies;
+        server_mgr.proxy_auth = mgr.proxy_auth;
     }
 
     server_mgr.user_key = user_key;
     server_mgr.data_key = data_key;
+
+    let proxy_auth = std::mem::take(&mut server_mgr.proxy_auth);
+    if server_mgr.migrate_proxy(proxy_auth) {
+        server_mgr.save()?;
+        server_mgr.save_config()?;
+    }
+
+    let tunnels: Vec<(u32, String, TunnelSpec)> = server_mgr
+        .servers
+        .iter()
+        .flat_map(|(k, v)| {
+            v.tunnels
+                .iter()
+                .filter(|t| t.auto_start)
+                .map(|t| (*k, v.name.clone(), t.clone()))
+        })
+        .collect();
+    drop(server_mgr);
+
+    tunnel_autostart(&wnd, &tunnel_mgr, tunnels).await;
     Ok(())
 }
 
@@ -223,11 +502,21 @@ pub async fn ssh_add_server(
 }
 
 #[tauri::command]
-pub async fn ssh_del_server(id: String, stat: State<'_, ServerContext>) -> Result<(), Error> {
+pub async fn ssh_del_server(
+    id: String,
+    stat: State<'_, ServerContext>,
+    pool: State<'_, SessionPool>,
+) -> Result<(), Error> {
     let key = id.parse::<u32>().map_err(into_essh)?;
     let mut server_mgr = stat.lock().await;
+    pool.forget(key);
 
     if server_mgr.servers.remove(&key).is_some() {
+        // 从其他服务器的跳板机链中移除
+        let key = key.to_string();
+        for v in server_mgr.servers.values_mut() {
+            v.jump_hosts.retain(|j| *j != key);
+        }
         server_mgr.save()?;
     }
     Ok(())
@@ -255,6 +544,7 @@ pub async fn ssh_update_server(
     id: String,
     server: ServerDetail,
     stat: State<'_, ServerContext>,
+    pool: State<'_, SessionPool>,
 ) -> Result<(), Error> {
     let key = format!(
         "{}/{}",
@@ -265,6 +555,8 @@ pub async fn ssh_update_server(
     let new_id = crc32fast::hash(key.as_bytes());
 
     let mut server_mgr = stat.lock().await;
+    // 连接参数可能已变化, 后续连接重新建立会话
+    pool.forget(old_id);
 
     if old_id != new_id {
         if server_mgr.servers.contains_key(&new_id) {
@@ -272,6 +564,14 @@ pub async fn ssh_update_server(
         }
 
         server_mgr.servers.remove(&old_id);
+
+        // 更新引用该服务器的跳板机链
+        let (old_key, new_key) = (old_id.to_string(), new_id.to_string());
+        for v in server_mgr.servers.values_mut() {
+            for j in v.jump_hosts.iter_mut().filter(|j| **j == old_key) {
+                j.clone_from(&new_key);
+            }
+        }
     }
 
     server_mgr.servers.insert(new_id, server);
@@ -281,7 +581,13 @@ pub async fn ssh_update_server(
 #[tauri::command]
 pub async fn ssh_config_all(stat: State<'_, ServerContext>) -> Result<Config, Error> {
     let server_mgr = stat.lock().await;
-    Ok(server_mgr.config.clone())
+    let mut config = server_mgr.config.clone();
+
+    if let Some(v) = server_mgr.proxies.get(DEFAULT_PROXY) {
+        config.proxy_addr = v.addr.clone();
+        config.proxy_scheme = v.scheme;
+    }
+    Ok(config)
 }
 
 #[tauri::command]
@@ -308,13 +614,100 @@ pub async fn ssh_set_config(
             server_mgr.config.expand_list = serde_json::from_str(&value).map_err(into_essh)?
         }
         ID_CFG_S_DGRP => server_mgr.config.server_group = value,
+        ID_CFG_RECONN => {
+            server_mgr.config.reconnect = serde_json::from_str(&value).map_err(into_essh)?
+        }
+        ID_CFG_SLOG => {
+            server_mgr.config.session_log = serde_json::from_str(&value).map_err(into_essh)?
+        }
         ID_CFG_S_VALS => {
             let vals: ConfigValues = serde_json::from_str(&value).map_err(into_essh)?;
+
+            // 设置界面的代理地址即 default 代理配置
+            if vals.proxy_addr.is_empty() && server_mgr.proxies.contains_key(DEFAULT_PROXY) {
+                server_mgr.proxy_in_use(DEFAULT_PROXY)?;
+            }
             server_mgr.config.font_name = vals.font_name;
-            server_mgr.config.proxy_addr = vals.proxy_addr;
+
+            if vals.proxy_addr.is_empty() {
+                server_mgr.proxies.remove(DEFAULT_PROXY);
+            } else {
+                let v = server_mgr
+                    .proxies
+                    .entry(DEFAULT_PROXY.to_string())
+                    .or_insert_with(|| ProxyProfile {
+                        name: DEFAULT_PROXY.to_string(),
+                        ..Default::default()
+                    });
+                v.addr = vals.proxy_addr;
+                v.scheme = vals.proxy_scheme;
+            }
+            server_mgr.save().map_err(into_essh)?;
         }
         _ => return Err(into_essh(anyhow::anyhow!("invalid config id:{id}"))),
     }
     server_mgr.save_config().map_err(into_essh)?;
     Ok(())
 }
+
+#[tauri::command]
+pub async fn ssh_get_proxies(stat: State<'_, ServerContext>) -> Result<Vec<ProxyProfile>, Error> {
+    let server_mgr = stat.lock().await;
+    Ok(server_mgr.proxies.values().cloned().collect())
+}
+
+#[tauri::command]
+pub async fn ssh_save_proxy(
+    proxy: ProxyProfile,
+    stat: State<'_, ServerContext>,
+) -> Result<(), Error> {
+    if proxy.name.is_empty() || proxy.addr.is_empty() {
+        return Err(anyhow::anyhow!("invalid proxy:{}", proxy.name).into());
+    }
+
+    let mut server_mgr = stat.lock().await;
+    server_mgr.proxies.insert(proxy.name.clone(), proxy);
+    server_mgr.save().map_err(into_essh)
+}
+
+#[tauri::command]
+pub async fn ssh_del_proxy(name: String, stat: State<'_, ServerContext>) -> Result<(), Error> {
+    let mut server_mgr = stat.lock().await;
+    server_mgr.proxy_in_use(&name)?;
+
+    if server_mgr.proxies.remove(&name).is_some() {
+        server_mgr.save()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reconnect_delay_backoff() {
+        let policy = ReconnectPolicy {
+            enabled: true,
+            max_attempts: 10,
+            initial_delay: 1000,
+            max_delay: 30000,
+        };
+        assert_eq!(policy.delay(0), Duration::from_millis(1000));
+        assert_eq!(policy.delay(1), Duration::from_millis(1000));
+        assert_eq!(policy.delay(2), Duration::from_millis(2000));
+        assert_eq!(policy.delay(5), Duration::from_millis(16000));
+        assert_eq!(policy.delay(6), Duration::from_millis(30000));
+        assert_eq!(policy.delay(u32::MAX), Duration::from_millis(30000));
+    }
+
+    #[test]
+    fn reconnect_delay_no_overflow() {
+        let policy = ReconnectPolicy {
+            initial_delay: u64::MAX / 2,
+            max_delay: u64::MAX,
+            ..Default::default()
+        };
+        assert_eq!(policy.delay(20), Duration::from_millis(u64::MAX));
+    }
+}
@@ -0,0 +1,319 @@
+use crate::{
+    record::safe_file_name,
+    server::{ServerContext, SessionLogConfig},
+    ssh::{into_essh, Error, SShMgr},
+};
+use anyhow::Result;
+use std::{
+    fs::File,
+    io::Write,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tauri::State;
+
+pub const LOG_DIR: &str = "logs";
+
+// 转义序列可能被拆分在两次读取之间, 需要保留解析状态
+#[derive(Clone, Copy, Default)]
+enum AnsiState {
+    #[default]
+    Text,
+    Esc,
+    // ESC ( B 等带一个参数字符的序列
+    EscArg,
+    Csi,
+    // OSC/DCS 以 BEL 或 ESC \ 结束
+    Osc,
+    OscEsc,
+}
+
+pub struct SessionLogger {
+    opts: SessionLogConfig,
+    path: PathBuf,
+    file: File,
+    size: u64,
+    ansi: AnsiState,
+    line_start: bool,
+}
+
+pub type SharedLogger = Arc<Mutex<Option<SessionLogger>>>;
+
+impl SessionLogger {
+    pub fn create(path: PathBuf, opts: SessionLogConfig) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            opts,
+            path,
+            file,
+            size,
+            ansi: AnsiState::Text,
+            line_start: true,
+        })
+    }
+
+    fn strip_ansi(&mut self, data: &str) -> String {
+        let mut text = String::with_capacity(data.len());
+
+        for c in data.chars() {
+            self.ansi = match (self.ansi, c) {
+                (AnsiState::Text, '\x1b') => AnsiState::Esc,
+                (AnsiState::Text, '\n' | '\t') => {
+                    text.push(c);
+                    AnsiState::Text
+                }
+                // 其他控制字符 (\r, BEL, 退格等) 不写入日志
+                (AnsiState::Text, c) if c.is_control() => AnsiState::Text,
+                (AnsiState::Text, c) => {
+                    text.push(c);
+                    AnsiState::Text
+                }
+                (AnsiState::Esc, '[') => AnsiState::Csi,
+                (AnsiState::Esc, ']' | 'P' | '_' | '^') => AnsiState::Osc,
+                (AnsiState::Esc, '(' | ')' | '*' | '+' | '#' | '%') => AnsiState::EscArg,
+                (AnsiState::Esc | AnsiState::EscArg, _) => AnsiState::Text,
+                (AnsiState::Csi, '\x40'..='\x7e') => AnsiState::Text,
+                (AnsiState::Csi, _) => AnsiState::Csi,
+                (AnsiState::Osc, '\x07') => AnsiState::Text,
+                (AnsiState::Osc, '\x1b') => AnsiState::OscEsc,
+                (AnsiState::Osc, _) => AnsiState::Osc,
+                (AnsiState::OscEsc, '\\') => AnsiState::Text,
+                (AnsiState::OscEsc, _) => AnsiState::Osc,
+            };
+        }
+
+        text
+    }
+
+    fn add_timestamp(&mut self, data: &str) -> String {
+        let prefix = chrono::Local::now()
+            .format("[%Y-%m-%d %H:%M:%S] ")
+            .to_string();
+        let mut text = String::with_capacity(data.len() + prefix.len());
+
+        for line in data.split_inclusive('\n') {
+            if self.line_start {
+                text.push_str(&prefix);
+            }
+            text.push_str(line);
+            self.line_start = line.ends_with('\n');
+        }
+
+        text
+    }
+
+    pub fn write(&mut self, data: &str) {
+        let mut text = match self.opts.strip_ansi {
+            true => self.strip_ansi(data),
+            false => data.to_string(),
+        };
+        if self.opts.timestamp {
+            text = self.add_timestamp(&text);
+        }
+
+        if self.file.write_all(text.as_bytes()).is_err() {
+            return;
+        }
+        self.size += text.len() as u64;
+
+        if self.opts.max_size > 0 && self.size >= self.opts.max_size {
+            self.rotate().ok();
+        }
+    }
+
+    // xxx.log -> xxx.log.1 -> xxx.log.2 ..., 超过 max_files 的删除
+    fn rotate(&mut self) -> Result<()> {
+        let backup = |n: u32| PathBuf::from(format!("{}.{n}", self.path.display()));
+
+        let max_files = self.opts.max_files;
+        if max_files > 0 {
+            std::fs::remove_file(backup(max_files)).ok();
+            for n in (1..max_files).rev() {
+                std::fs::rename(backup(n), backup(n + 1)).ok();
+            }
+            std::fs::rename(&self.path, backup(1))?;
+        }
+
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+pub fn log_with(logger: &SharedLogger, data: &str) {
+    if let Some(v) = logger.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        v.write(data);
+    }
+}
+
+// 支持 {server} {date} {time} {id}, 结果总是在程序目录的 logs 下,
+// 去掉 .. 和根路径等部分, 避免写到 logs 之外
+fn log_path(app_path: &Path, pattern: &str, server_name: &str, id: u32) -> PathBuf {
+    let now = chrono::Local::now();
+    let name = pattern
+        .replace("{server}", &safe_file_name(server_name))
+        .replace("{date}", &now.format("%Y%m%d").to_string())
+        .replace("{time}", &now.format("%H%M%S").to_string())
+        .replace("{id}", &id.to_string());
+
+    let mut rel: PathBuf = Path::new(&name)
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(v) => Some(v),
+            _ => None,
+        })
+        .collect();
+    if rel.as_os_str().is_empty() {
+        rel = PathBuf::from(format!("{id}.log"));
+    }
+    app_path.join(LOG_DIR).join(rel)
+}
+
+pub fn auto_logger(
+    opts: &SessionLogConfig,
+    app_path: &Path,
+    server_name: &str,
+    id: u32,
+) -> SharedLogger {
+    let logger = match opts.enabled {
+        true => {
+            let path = log_path(app_path, &opts.pattern, server_name, id);
+            SessionLogger::create(path, opts.clone()).ok()
+        }
+        false => None,
+    };
+    Arc::new(Mutex::new(logger))
+}
+
+#[tauri::command]
+pub async fn ssh_log_start(
+    id: u32,
+    stat: State<'_, SShMgr>,
+    svr_ctx: State<'_, ServerContext>,
+) -> Result<String, Error> {
+    let ctx = stat
+        .lock()
+        .await
+        .contexts
+        .get(&id)
+        .ok_or(anyhow::anyhow!("ssh context not found"))?
+        .clone();
+
+    let (server_id, logger) = {
+        let l = ctx.lock().await;
+        (l.server_id, l.logger.clone())
+    };
+
+    let lsm = svr_ctx.lock().await;
+    let server = lsm
+        .servers
+        .get(&server_id)
+        .ok_or(anyhow::anyhow!("server not found"))?;
+    let opts = lsm.config.session_log.clone();
+    let path = log_path(&lsm.app_path, &opts.pattern, &server.name, id);
+    drop(lsm);
+
+    let v = SessionLogger::create(path.clone(), opts).map_err(into_essh)?;
+    *logger.lock().unwrap_or_else(|e| e.into_inner()) = Some(v);
+
+    Ok(path.to_string_lossy().to_string())
+}
+
+#[tauri::command]
+pub async fn ssh_log_stop(id: u32, stat: State<'_, SShMgr>) -> Result<(), Error> {
+    let ctx = stat.lock().await.contexts.get(&id).cloned();
+    if let Some(ctx) = ctx {
+        let logger = ctx.lock().await.logger.clone();
+        logger.lock().unwrap_or_else(|e| e.into_inner()).take();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_logger(name: &str, opts: SessionLogConfig) -> (SessionLogger, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("xtermrs-log-{}-{name}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        let path = dir.join("test.log");
+        (SessionLogger::create(path.clone(), opts).unwrap(), dir)
+    }
+
+    #[test]
+    fn strip_ansi_sequences() {
+        let (mut logger, dir) = temp_logger("strip", SessionLogConfig::default());
+        let text = logger
+            .strip_ansi("\x1b[1;32mok\x1b[0m\r\n\x1b]0;title\x07$ \x1b(Bls\x08\t\x1bP1$r\x1b\\x\n");
+        assert_eq!(text, "ok\n$ ls\tx\n");
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn strip_ansi_split_reads() {
+        let (mut logger, dir) = temp_logger("split", SessionLogConfig::default());
+        assert_eq!(logger.strip_ansi("a\x1b"), "a");
+        assert_eq!(logger.strip_ansi("[31"), "");
+        assert_eq!(logger.strip_ansi("mb\x1b]2;t"), "b");
+        assert_eq!(logger.strip_ansi("itle\x1b"), "");
+        assert_eq!(logger.strip_ansi("\\c"), "c");
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn timestamp_per_line() {
+        let (mut logger, dir) = temp_logger("stamp", SessionLogConfig::default());
+        let text = logger.add_timestamp("one\ntw");
+        let lines: Vec<&str> = text.split_inclusive('\n').collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with('[') && lines[0].ends_with("] one\n"));
+        assert!(lines[1].ends_with("] tw"));
+
+        // 上次没有换行结尾, 不再加前缀
+        assert_eq!(logger.add_timestamp("o\n"), "o\n");
+        assert!(logger.add_timestamp("three").ends_with("] three"));
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn rotate_by_size() {
+        let opts = SessionLogConfig {
+            max_size: 10,
+            max_files: 2,
+            ..Default::default()
+        };
+        let (mut logger, dir) = temp_logger("rotate", opts);
+        for v in ["first-----", "second----", "third-----"] {
+            logger.write(v);
+        }
+
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).ok();
+        assert_eq!(read("test.log").as_deref(), Some(""));
+        assert_eq!(read("test.log.1").as_deref(), Some("third-----"));
+        assert_eq!(read("test.log.2").as_deref(), Some("second----"));
+        assert_eq!(read("test.log.3"), None);
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn log_path_stays_in_log_dir() {
+        let app = Path::new("/app");
+        let logs = app.join(LOG_DIR);
+        assert_eq!(log_path(app, "../../x", "s", 1), logs.join("x"));
+        assert_eq!(log_path(app, "/etc/foo", "s", 1), logs.join("etc/foo"));
+        assert_eq!(
+            log_path(app, "{server}-{id}.log", "a/b", 7),
+            logs.join("a_b-7.log")
+        );
+        assert_eq!(log_path(app, "..", "s", 3), logs.join("3.log"));
+    }
+}
@@ -1,28 +1,41 @@
 use crate::{
-    proxy::ssh_proxy_connect,
-    server::{Config, ServerContext, ServerDetail},
+    broadcast::BroadcastGroup,
+    known_hosts::{prefer_known_host_keys, verify_host_key, HostKeyChanged},
+    pool::{session_acquire, PooledSession, SessionPool},
+    prompt::{AuthPrompt, KbdPrompter, PromptMgr, PromptSink},
+    proxy::{ssh_jump_connect, ssh_proxy_connect},
+    record::{auto_recorder, record_with, SharedRecorder},
+    server::{AuthMode, ConnectInfo, ReconnectPolicy, ServerContext, ServerDetail},
+    session_log::{auto_logger, log_with, SharedLogger},
 };
 use anyhow::Result;
 use async_ssh2_lite::{
-    ssh2::ExtendedData, AsyncChannel, AsyncSession, SessionConfiguration, TokioTcpStream,
+    ssh2::ExtendedData, AsyncChannel, AsyncSession, AsyncStream, SessionConfiguration,
+    TokioTcpStream,
 };
 use futures_util::AsyncReadExt;
 use serde::{Deserialize, Serialize, Serializer};
 use serde_json::json;
 use std::{
     collections::HashMap,
+    iter,
+    path::Path,
     sync::{
         atomic::{AtomicU32, Ordering},
         Arc,
     },
 };
-use tauri::{async_runtime::Mutex, ipc::Channel, State};
+use tauri::{async_runtime::Mutex, ipc::Channel, AppHandle, Manager, State};
 use tokio::{io::AsyncWriteExt, sync::mpsc};
 
 static SSH_ID_MGR: AtomicU32 = AtomicU32::new(100);
-const CMD_DATA: i32 = 0;
-const CMD_RESIZE: i32 = 1;
+pub const CMD_DATA: i32 = 0;
+pub const CMD_RESIZE: i32 = 1;
 const CMD_CLOSE: i32 = 2;
+pub const CMD_PROMPT: i32 = 3;
+const CMD_RECONNECTING: i32 = 4;
+const CMD_RECONNECTED: i32 = 5;
+pub const CMD_PLAY_END: i32 = 6;
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct SshMessage {
@@ -30,21 +43,38 @@ pub struct SshMessage {
     pub data: String,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
 pub struct TerminalSize {
-    cols: u32,
-    rows: u32,
+    pub cols: u32,
+    pub rows: u32,
     width: u32,
     height: u32,
 }
 
 pub struct SshContext {
-    //pub session: AsyncSession<TokioTcpStream>,
+    pub server_id: u32,
+    // 会话来自连接池, 与其他终端、文件传输和隧道共享
+    session: PooledSession,
     channel: AsyncChannel<TokioTcpStream>,
     tx: mpsc::Sender<()>,
+    // 重连后恢复终端大小
+    pub size: Option<TerminalSize>,
+    pub recorder: SharedRecorder,
+    pub logger: SharedLogger,
+}
+
+enum LoopExit {
+    Closed,
+    Dropped,
+}
+
+#[derive(Default)]
+pub struct SshSessions {
+    pub contexts: HashMap<u32, Arc<Mutex<SshContext>>>,
+    pub groups: HashMap<u32, BroadcastGroup>,
 }
 
-pub type SShMgr = Mutex<HashMap<u32, Arc<Mutex<SshContext>>>>;
+pub type SShMgr = Mutex<SshSessions>;
 pub struct Error(anyhow::Error);
 impl From<anyhow::Error> for Error {
     fn from(err: anyhow::Error) -> Self {
@@ -57,6 +87,9 @@ impl Serialize for Error {
     where
         S: Serializer,
     {
+        if let Some(e) = self.0.downcast_ref::<HostKeyChanged>() {
+            return e.serialize(serializer);
+        }
         serializer.serialize_str(self.0.to_string().as_str())
     }
 }
@@ -66,43 +99,131 @@ pub fn into_essh<E: Into<anyhow::Error>>(err: E) -> Error {
 }
 
 pub async fn ssh_create_session(
-    server: &ServerDetail,
-    cfg: &Config,
+    info: &ConnectInfo,
+    prompt: &AuthPrompt<'_>,
 ) -> Result<AsyncSession<TokioTcpStream>> {
-    let stream = if server.use_proxy && !cfg.proxy_addr.is_empty() {
-        ssh_proxy_connect(server, cfg).await?
-    } else {
-        TokioTcpStream::connect((server.host.as_str(), server.port)).await?
+    let mut hops = info.jumps.iter().chain(iter::once(&info.server));
+    let first = hops.next().unwrap_or(&info.server);
+
+    let stream = match &info.proxy {
+        Some(proxy) => ssh_proxy_connect(first, proxy).await?,
+        None => TokioTcpStream::connect((first.host.as_str(), first.port)).await?,
     };
 
+    let mut session = ssh_open_session(stream, first, &info.app_path, prompt).await?;
+
+    // 逐跳通过 direct-tcpip 通道连接, 最后一跳即目标服务器
+    for hop in hops {
+        let stream = ssh_jump_connect(&session, hop).await?;
+        session = ssh_open_session(stream, hop, &info.app_path, prompt).await?;
+    }
+
+    Ok(session)
+}
+
+async fn ssh_open_session(
+    stream: TokioTcpStream,
+    server: &ServerDetail,
+    app_path: &Path,
+    prompt: &AuthPrompt<'_>,
+) -> Result<AsyncSession<TokioTcpStream>> {
     let mut configuration = SessionConfiguration::new();
     configuration.set_timeout(15000);
     configuration.set_keepalive(true, 60);
 
     let mut session = AsyncSession::new(stream, Some(configuration))?;
 
+    prefer_known_host_keys(&session, app_path, &server.host, server.port).await?;
     session.handshake().await?;
+    verify_host_key(&session, app_path, &server.host, server.port)?;
 
+    ssh_authenticate(&session, server, prompt).await?;
+
+    if !session.authenticated() {
+        anyhow::bail!("{} authenticated failed", server.username);
+    }
+
+    Ok(session)
+}
+
+// 部分成功(如 AuthenticationMethods publickey,keyboard-interactive)后服务器只列出剩余的认证方式
+async fn remaining_methods(
+    session: &AsyncSession<TokioTcpStream>,
+    username: &str,
+    methods: &mut String,
+) {
+    if let Ok(v) = session.auth_methods(username).await {
+        *methods = v.to_string();
+    }
+}
+
+async fn ssh_authenticate(
+    session: &AsyncSession<TokioTcpStream>,
+    server: &ServerDetail,
+    prompt: &AuthPrompt<'_>,
+) -> Result<()> {
+    let mut methods = session.auth_methods(&server.username).await?.to_string();
+    if session.authenticated() {
+        return Ok(());
+    }
+
+    if server.auth_mode == AuthMode::Agent {
+        // 逐个尝试 agent (SSH_AUTH_SOCK) 中的身份, 失败后回退
+        let ret = session.userauth_agent_with_try_next(&server.username).await;
+        if ret.is_ok() && session.authenticated() {
+            return Ok(());
+        }
+        remaining_methods(session, &server.username, &mut methods).await;
+    }
+
+    let mut ret = Err(anyhow::anyhow!("unsupported auth methods:{methods}"));
     if !server.cert_path.is_empty() {
         let privatekey = server.cert_path.as_ref();
         let passphrase = match server.cert_pass.is_empty() {
             true => None,
             false => Some(server.cert_pass.as_ref()),
         };
-        session
+        let r = session
             .userauth_pubkey_file(&server.username, None, privatekey, passphrase)
-            .await?;
-    } else {
-        session
+            .await;
+        if session.authenticated() {
+            return Ok(());
+        }
+        remaining_methods(session, &server.username, &mut methods).await;
+        ret = r.map_err(Into::into);
+    }
+
+    if methods.contains("password") {
+        ret = session
             .userauth_password(&server.username, &server.password)
-            .await?;
+            .await
+            .map_err(Into::into);
+        if session.authenticated() {
+            return Ok(());
+        }
+        remaining_methods(session, &server.username, &mut methods).await;
     }
 
-    if !session.authenticated() {
-        anyhow::bail!("{} authenticated failed", server.username);
+    // PAM/OTP 等多因素认证
+    // 提示回调会同步等待用户输入, 放到阻塞线程中执行
+    if methods.contains("keyboard-interactive") {
+        let mut prompter = KbdPrompter::new(server, prompt);
+        let (session, username) = (session.clone(), server.username.clone());
+        let handle = tokio::runtime::Handle::current();
+        let (r, prompter) = tokio::task::spawn_blocking(move || {
+            let r =
+                handle.block_on(session.userauth_keyboard_interactive(&username, &mut prompter));
+            (r, prompter)
+        })
+        .await?;
+
+        if prompter.cancelled {
+            anyhow::bail!("authentication cancelled");
+        }
+        ret = r.map_err(Into::into);
     }
 
-    Ok(session)
+    ret
 }
 
 // UTF-8编码的字节结构如下：
@@ -140,92 +261,250 @@ fn calc_utf8_remaining(data: &[u8]) -> usize {
     n
 }
 
+async fn ssh_open_terminal(
+    session: &AsyncSession<TokioTcpStream>,
+    size: Option<TerminalSize>,
+) -> Result<AsyncChannel<TokioTcpStream>> {
+    let mut channel = session.channel_session().await?;
+
+    channel.handle_extended_data(ExtendedData::Merge).await?;
+
+    let dim = size.map(|ts| (ts.cols, ts.rows, ts.width, ts.height));
+    channel.request_pty("xterm", None, dim).await?;
+
+    channel.shell().await?;
+    Ok(channel)
+}
+
+async fn ssh_read_loop(
+    session: &PooledSession,
+    stream: &mut AsyncStream<TokioTcpStream>,
+    rx: &mut mpsc::Receiver<()>,
+    on_message: &Channel<serde_json::Value>,
+    recorder: &SharedRecorder,
+    logger: &SharedLogger,
+) -> LoopExit {
+    let mut tmp_vec = vec![0u8; 16 * 1024];
+    let buf = tmp_vec.as_mut_slice();
+    let mut idx = 0;
+
+    loop {
+        tokio::select! {
+            _ = rx.recv() => return LoopExit::Closed,
+            nr = stream.read(&mut buf[idx..]) => {
+                let mut nlen = match nr {
+                    Ok(v) => v,
+                    Err(_) => return LoopExit::Dropped,
+                };
+
+                // 通道正常结束(如 exit)时会话仍可用, 只有传输断开才重连
+                if nlen == 0 {
+                    return match session.keepalive_send().await {
+                        Ok(_) => LoopExit::Closed,
+                        Err(_) => LoopExit::Dropped,
+                    };
+                }
+
+                nlen += idx;
+                let db = &mut buf[..nlen];
+
+                // 解决半个utf8字符的问题
+                idx = calc_utf8_remaining(db);
+                let (mut l, r) = db.split_at_mut(nlen - idx);
+
+                let dm = SshMessage {
+                    code: CMD_DATA,
+                    data: String::from_utf8_lossy(l).to_string(),
+                };
+
+                if idx > 0 {
+                    std::io::copy(&mut &(*r), &mut l).ok();
+                }
+
+                record_with(recorder, |v| v.output(&dm.data));
+                log_with(logger, &dm.data);
+
+                let json_value = match serde_json::to_value(dm) {
+                    Ok(v) => v,
+                    Err(_) => {
+                        // log
+                        return LoopExit::Closed;
+                    }
+                };
+
+                if let Err(_e) = on_message.send(json_value) {
+                    // log
+                    return LoopExit::Closed;
+                }
+            }
+        }
+    }
+}
+
+// 按策略重连, 成功后替换 SshContext 中的 channel, 保持 id 不变
+async fn ssh_reconnect(
+    id: u32,
+    ctx: &Mutex<SshContext>,
+    rx: &mut mpsc::Receiver<()>,
+    on_message: &Channel<serde_json::Value>,
+    info: &ConnectInfo,
+    policy: &ReconnectPolicy,
+    app: &AppHandle,
+) -> bool {
+    let pool = app.state::<SessionPool>();
+    let prompt_mgr = app.state::<PromptMgr>();
+    let prompt = AuthPrompt {
+        mgr: &prompt_mgr,
+        sink: PromptSink::Channel(on_message.clone()),
+    };
+    let mut error = String::new();
+
+    for attempt in 1..=policy.max_attempts {
+        let delay = policy.delay(attempt);
+        on_message
+            .send(json!({
+                "code": CMD_RECONNECTING,
+                "data": {
+                    "id": id,
+                    "attempt": attempt,
+                    "max_attempts": policy.max_attempts,
+                    "delay": delay.as_millis() as u64,
+                    "error": error,
+                },
+            }))
+            .ok();
+
+        tokio::select! {
+            _ = rx.recv() => return false,
+            _ = tokio::time::sleep(delay) => {}
+        }
+
+        let (server_id, size) = {
+            let l = ctx.lock().await;
+            // 断线的会话不再复用
+            l.session.invalidate();
+            (l.server_id, l.size)
+        };
+
+        // 连接和认证期间关闭终端时立即放弃, 不再恢复会话
+        let connect = async {
+            let session = session_acquire(&pool, server_id, info, &prompt).await?;
+            let channel = ssh_open_terminal(&session, size).await?;
+            anyhow::Ok((session, channel))
+        };
+        let ret = tokio::select! {
+            _ = rx.recv() => return false,
+            v = connect => v,
+        };
+
+        match ret {
+            Ok((session, channel)) => {
+                let mut l = ctx.lock().await;
+                l.session = session;
+                l.channel = channel;
+                drop(l);
+                on_message
+                    .send(json!({
+                        "code": CMD_RECONNECTED,
+                        "data": {
+                            "id": id,
+                            "attempt": attempt,
+                        },
+                    }))
+                    .ok();
+                return true;
+            }
+            Err(e) => error = e.to_string(),
+        }
+    }
+
+    false
+}
+
 #[tauri::command]
 pub async fn ssh_connect(
     id: String,
     on_message: Channel<serde_json::Value>,
+    app: AppHandle,
     ssh_mgr: State<'_, SShMgr>,
     svr_ctx: State<'_, ServerContext>,
+    prompt_mgr: State<'_, PromptMgr>,
+    pool: State<'_, SessionPool>,
 ) -> Result<u32, Error> {
     let id_key = id.parse::<u32>().map_err(into_essh)?;
     let lsm = svr_ctx.lock().await;
-    let cfg = lsm.config.clone();
-    let server = lsm
-        .servers
-        .get(&id_key)
-        .ok_or(into_essh(anyhow::anyhow!("server not found")))?
-        .clone();
-
+    let info = lsm.connect_info(id_key)?;
+    let policy = lsm.config.reconnect.clone();
+    let log_opts = lsm.config.session_log.clone();
     drop(lsm);
 
-    let session = ssh_create_session(&server, &cfg).await?;
-
-    let (tx, mut rx) = mpsc::channel::<()>(10);
-
-    let mut channel = session.channel_session().await.map_err(into_essh)?;
-
-    channel
-        .handle_extended_data(ExtendedData::Merge)
-        .await
-        .map_err(into_essh)?;
+    let prompt = AuthPrompt {
+        mgr: &prompt_mgr,
+        sink: PromptSink::Channel(on_message.clone()),
+    };
+    let session = session_acquire(&pool, id_key, &info, &prompt).await?;
+    let channel = ssh_open_terminal(&session, None).await?;
 
-    channel
-        .request_pty("xterm", None, None)
-        .await
-        .map_err(into_essh)?;
+    let (tx, rx) = mpsc::channel::<()>(10);
+    let id = SSH_ID_MGR.fetch_add(1, Ordering::Release);
+    let ctx = Arc::new(Mutex::new(SshContext {
+        tx,
+        server_id: id_key,
+        session,
+        channel,
+        size: None,
+        recorder: auto_recorder(&info, id, None),
+        logger: auto_logger(&log_opts, &info.app_path, &info.server.name, id),
+    }));
+
+    ssh_spawn_loop(id, ctx.clone(), rx, on_message, info, policy, app);
 
-    channel.shell().await.map_err(into_essh)?;
+    let mut l = ssh_mgr.lock().await;
+    l.contexts.insert(id, ctx);
 
-    let id = SSH_ID_MGR.fetch_add(1, Ordering::Release);
-    let mut stream = channel.stream(0);
+    Ok(id)
+}
 
+fn ssh_spawn_loop(
+    id: u32,
+    ctx: Arc<Mutex<SshContext>>,
+    mut rx: mpsc::Receiver<()>,
+    on_message: Channel<serde_json::Value>,
+    info: ConnectInfo,
+    policy: ReconnectPolicy,
+    app: AppHandle,
+) {
     tauri::async_runtime::spawn(async move {
-        let mut tmp_vec = vec![0u8; 16 * 1024];
-        let buf = tmp_vec.as_mut_slice();
-        let mut idx = 0;
-
         loop {
-            tokio::select! {
-                _ = rx.recv() => break,
-                nr = stream.read(&mut buf[idx..]) => {
-                    let mut nlen = match nr {
-                        Ok(v) => v,
-                        Err(_) => break,
-                    };
-
-                    if nlen == 0 {
-                        break;
-                    }
-
-                    nlen += idx;
-                    let db = &mut buf[..nlen];
-
-                    // 解决半个utf8字符的问题
-                    idx = calc_utf8_remaining(db);
-                    let (mut l, r) = db.split_at_mut(nlen - idx);
-
-                    let dm = SshMessage {
-                        code: CMD_DATA,
-                        data: String::from_utf8_lossy(l).to_string(),
-                    };
-
-                    if idx > 0 {
-                        std::io::copy(&mut &(*r), &mut l).ok();
-                    }
+            let (session, mut stream, recorder, logger) = {
+                let l = ctx.lock().await;
+                (
+                    l.session.clone(),
+                    l.channel.stream(0),
+                    l.recorder.clone(),
+                    l.logger.clone(),
+                )
+            };
+            let exit = ssh_read_loop(
+                &session,
+                &mut stream,
+                &mut rx,
+                &on_message,
+                &recorder,
+                &logger,
+            )
+            .await;
+            drop(session);
+            if let LoopExit::Closed = exit {
+                break;
+            }
 
-                    let json_value = match serde_json::to_value(dm) {
-                        Ok(v) => v,
-                        Err(_) => {
-                            // log
-                            break;
-                        }
-                    };
+            if !policy.enabled {
+                break;
+            }
 
-                    if let Err(_e) = on_message.send(json_value) {
-                        // log
-                        break;
-                    }
-                }
+            if !ssh_reconnect(id, &ctx, &mut rx, &on_message, &info, &policy, &app).await {
+                break;
             }
         }
         // 通知前端报错，链接断开
@@ -236,16 +515,54 @@ pub async fn ssh_connect(
             }))
             .ok();
     });
+}
+
+// 在已有终端的会话上再开一个 shell, 不重新连接和认证
+#[tauri::command]
+pub async fn ssh_duplicate(
+    id: u32,
+    on_message: Channel<serde_json::Value>,
+    app: AppHandle,
+    ssh_mgr: State<'_, SShMgr>,
+    svr_ctx: State<'_, ServerContext>,
+) -> Result<u32, Error> {
+    let src = ssh_mgr
+        .lock()
+        .await
+        .contexts
+        .get(&id)
+        .ok_or(anyhow::anyhow!("ssh context not found"))?
+        .clone();
+
+    let (server_id, session, size) = {
+        let l = src.lock().await;
+        (l.server_id, l.session.clone(), l.size)
+    };
+
+    let lsm = svr_ctx.lock().await;
+    let info = lsm.connect_info(server_id)?;
+    let policy = lsm.config.reconnect.clone();
+    let log_opts = lsm.config.session_log.clone();
+    drop(lsm);
+
+    let channel = ssh_open_terminal(&session, size).await?;
+
+    let (tx, rx) = mpsc::channel::<()>(10);
+    let id = SSH_ID_MGR.fetch_add(1, Ordering::Release);
+    let ctx = Arc::new(Mutex::new(SshContext {
+        tx,
+        server_id,
+        session,
+        channel,
+        size,
+        recorder: auto_recorder(&info, id, size),
+        logger: auto_logger(&log_opts, &info.app_path, &info.server.name, id),
+    }));
+
+    ssh_spawn_loop(id, ctx.clone(), rx, on_message, info, policy, app);
 
     let mut l = ssh_mgr.lock().await;
-    l.insert(
-        id,
-        Arc::new(Mutex::new(SshContext {
-            tx,
-            //session,
-            channel,
-        })),
-    );
+    l.contexts.insert(id, ctx);
 
     Ok(id)
 }
@@ -255,10 +572,17 @@ pub async fn ssh_send(stat: State<'_, SShMgr>, id: u32, msg: SshMessage) -> Resu
     let l1 = stat.lock().await;
 
     let ctx = l1
+        .contexts
         .get(&id)
         .ok_or(anyhow::anyhow!("ssh context not found"))?
         .clone();
 
+    // 只广播输入, 各终端大小独立
+    let peers = match msg.code {
+        CMD_DATA => l1.broadcast_peers(id),
+        _ => Vec::new(),
+    };
+
     drop(l1);
 
     let mut l2 = ctx.lock().await;
@@ -269,9 +593,20 @@ pub async fn ssh_send(stat: State<'_, SShMgr>, id: u32, msg: SshMessage) -> Resu
                 .write_all(msg.data.as_bytes())
                 .await
                 .map_err(into_essh)?;
+            record_with(&l2.recorder, |v| v.input(&msg.data));
+            drop(l2);
+
+            for v in peers {
+                let mut l3 = v.lock().await;
+                if l3.channel.write_all(msg.data.as_bytes()).await.is_ok() {
+                    record_with(&l3.recorder, |v| v.input(&msg.data));
+                }
+            }
         }
         CMD_RESIZE => {
             let ts: TerminalSize = serde_json::from_str(&msg.data).map_err(into_essh)?;
+            l2.size = Some(ts);
+            record_with(&l2.recorder, |v| v.resize(ts));
             l2.channel
                 .request_pty_size(ts.cols, ts.rows, Some(ts.width), Some(ts.height))
                 .await
@@ -288,7 +623,10 @@ pub async fn ssh_send(stat: State<'_, SShMgr>, id: u32, msg: SshMessage) -> Resu
 #[tauri::command]
 pub async fn ssh_close(stat: State<'_, SShMgr>, id: u32) -> Result<(), Error> {
     let mut l = stat.lock().await;
-    if let Some(v) = l.remove(&id) {
+    for g in l.groups.values_mut() {
+        g.members.remove(&id);
+    }
+    if let Some(v) = l.contexts.remove(&id) {
         v.lock().await.tx.send(()).await.ok();
     }
     Ok(())
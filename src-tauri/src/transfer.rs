@@ -0,0 +1,487 @@
+use crate::{
+    download::download_start,
+    ssh::Error,
+    upload::{upload_start, TransferOptions},
+};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{
+        atomic::{AtomicU32, AtomicU8, Ordering},
+        Arc,
+    },
+};
+use tauri::{async_runtime::Mutex, Emitter, Manager, State};
+use tokio::sync::oneshot;
+
+pub const ENT_TRANSFER: &str = "tauri://TransferMessage";
+
+pub static TRANSFER_ID_MGR: AtomicU32 = AtomicU32::new(1);
+
+const DEFAULT_CONCURRENCY: usize = 2;
+
+const TOKEN_RUN: u8 = 0;
+const TOKEN_PAUSE: u8 = 1;
+const TOKEN_CANCEL: u8 = 2;
+
+// 传输循环每个数据块检查一次, 暂停和取消都会让传输以 Interrupted 错误退出
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicU8>);
+
+impl CancelToken {
+    pub fn pause(&self) {
+        self.0.store(TOKEN_PAUSE, Ordering::Relaxed);
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(TOKEN_CANCEL, Ordering::Relaxed);
+    }
+
+    pub fn check(&self) -> Result<()> {
+        match self.0.load(Ordering::Relaxed) {
+            TOKEN_RUN => Ok(()),
+            TOKEN_PAUSE => Err(Interrupted::Paused.into()),
+            _ => Err(Interrupted::Cancelled.into()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interrupted {
+    Paused,
+    Cancelled,
+}
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Interrupted::Paused => write!(f, "transfer paused"),
+            Interrupted::Cancelled => write!(f, "transfer cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferState {
+    Queued,
+    Running,
+    Paused,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferKind {
+    Upload,
+    Download,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TransferJob {
+    pub kind: TransferKind,
+    pub server_id: String,
+    pub local_path: String,
+    pub remote_path: String,
+    #[serde(default)]
+    pub options: TransferOptions,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Resume {
+    No,
+    // 由队列暂停, 已传部分是自己写入的, 不需要校验
+    Trusted,
+    // 失败或取消后重试, 用户未开启续传时校验已传部分
+    Verify,
+}
+
+struct TransferEntry {
+    job: TransferJob,
+    state: TransferState,
+    error: String,
+    token: CancelToken,
+    // 已启动过的任务重新排队时从已传输的位置继续
+    started: bool,
+    resume: Resume,
+    // ssh_upload/ssh_download 等待任务结束
+    waiter: Option<oneshot::Sender<(TransferState, String)>>,
+    wnd: tauri::Window,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TransferInfo {
+    id: u32,
+    kind: TransferKind,
+    server_id: String,
+    local_path: String,
+    remote_path: String,
+    state: TransferState,
+    error: String,
+}
+
+pub struct TransferQueue {
+    jobs: BTreeMap<u32, TransferEntry>,
+    concurrency: usize,
+}
+
+impl Default for TransferQueue {
+    fn default() -> Self {
+        Self {
+            jobs: BTreeMap::new(),
+            concurrency: DEFAULT_CONCURRENCY,
+        }
+    }
+}
+
+pub type TransferMgr = Mutex<TransferQueue>;
+
+fn transfer_notify(wnd: &tauri::Window, id: u32, state: TransferState, error: &str) {
+    wnd.emit(
+        ENT_TRANSFER,
+        json!({
+            "id": id,
+            "state": state,
+            "error": error,
+        }),
+    )
+    .ok();
+}
+
+impl TransferEntry {
+    fn new(job: TransferJob, wnd: tauri::Window) -> Self {
+        Self {
+            job,
+            state: TransferState::Queued,
+            error: String::new(),
+            token: CancelToken::default(),
+            started: false,
+            resume: Resume::No,
+            waiter: None,
+            wnd,
+        }
+    }
+
+    fn set_state(&mut self, id: u32, state: TransferState, error: String) {
+        transfer_notify(&self.wnd, id, state, &error);
+        self.state = state;
+        self.error = error;
+
+        if self.finished() {
+            if let Some(tx) = self.waiter.take() {
+                tx.send((state, self.error.clone())).ok();
+            }
+        }
+    }
+
+    // 重新排队时使用新的 token, 并从已传输的位置继续
+    fn requeue(&mut self, id: u32) {
+        self.token = CancelToken::default();
+        if self.started && self.resume != Resume::Verify {
+            self.resume = match self.state {
+                TransferState::Paused => Resume::Trusted,
+                _ => Resume::Verify,
+            };
+        }
+        self.set_state(id, TransferState::Queued, String::new());
+    }
+
+    fn finished(&self) -> bool {
+        matches!(
+            self.state,
+            TransferState::Done | TransferState::Failed | TransferState::Cancelled
+        )
+    }
+
+    fn run_options(&self) -> TransferOptions {
+        let mut options = run_options(&self.job.options, self.resume);
+        options.token = self.token.clone();
+        options
+    }
+}
+
+// 不修改用户的选项, 只调整本次运行的续传方式
+fn run_options(options: &TransferOptions, resume: Resume) -> TransferOptions {
+    let mut options = options.clone();
+    match resume {
+        Resume::No => {}
+        Resume::Trusted => {
+            options.resume = true;
+            options.verify = false;
+        }
+        Resume::Verify if !options.resume => {
+            options.resume = true;
+            options.verify = true;
+        }
+        Resume::Verify => {}
+    }
+    options
+}
+
+impl TransferQueue {
+    // 按 id 顺序启动排队中的任务, 直到达到并发数
+    fn schedule(&mut self) {
+        let mut running = self
+            .jobs
+            .values()
+            .filter(|v| v.state == TransferState::Running)
+            .count();
+
+        for (id, v) in self.jobs.iter_mut() {
+            if running >= self.concurrency {
+                break;
+            }
+            if v.state != TransferState::Queued {
+                continue;
+            }
+
+            running += 1;
+            v.started = true;
+            v.set_state(*id, TransferState::Running, String::new());
+
+            let mut job = v.job.clone();
+            job.options = v.run_options();
+            tauri::async_runtime::spawn(transfer_run(*id, job, v.wnd.clone()));
+        }
+    }
+
+    fn entry_mut(&mut self, id: u32) -> Result<&mut TransferEntry> {
+        self.jobs
+            .get_mut(&id)
+            .ok_or(anyhow::anyhow!("transfer not found:{id}"))
+    }
+}
+
+async fn transfer_run(id: u32, job: TransferJob, wnd: tauri::Window) {
+    let ret = match job.kind {
+        TransferKind::Upload => {
+            upload_start(
+                &wnd,
+                id,
+                &job.server_id,
+                &job.local_path,
+                &job.remote_path,
+                &job.options,
+            )
+            .await
+        }
+        TransferKind::Download => {
+            download_start(
+                &wnd,
+                id,
+                &job.server_id,
+                &job.local_path,
+                &job.remote_path,
+                &job.options,
+            )
+            .await
+        }
+    };
+
+    let (state, error) = match ret {
+        Ok(_) => (TransferState::Done, String::new()),
+        Err(e) => match e.downcast_ref::<Interrupted>() {
+            Some(Interrupted::Paused) => (TransferState::Paused, String::new()),
+            Some(Interrupted::Cancelled) => (TransferState::Cancelled, String::new()),
+            None => (TransferState::Failed, e.to_string()),
+        },
+    };
+
+    let mgr = wnd.state::<TransferMgr>();
+    let mut l = mgr.lock().await;
+    if let Some(v) = l.jobs.get_mut(&id) {
+        v.set_state(id, state, error);
+    }
+    l.schedule();
+}
+
+// 登记到队列后等待结束, 期间可以按 id 暂停、继续和取消
+pub async fn transfer_wait(wnd: &tauri::Window, id: u32, job: TransferJob) -> Result<()> {
+    let (tx, rx) = oneshot::channel();
+    {
+        let mgr = wnd.state::<TransferMgr>();
+        let mut l = mgr.lock().await;
+        if l.jobs.contains_key(&id) {
+            anyhow::bail!("transfer exists:{id}");
+        }
+
+        let mut entry = TransferEntry::new(job, wnd.clone());
+        entry.waiter = Some(tx);
+        l.jobs.insert(id, entry);
+        l.schedule();
+    }
+
+    match rx.await? {
+        (TransferState::Done, _) => Ok(()),
+        (TransferState::Cancelled, _) => Err(Interrupted::Cancelled.into()),
+        (_, error) => Err(anyhow::anyhow!(error)),
+    }
+}
+
+#[tauri::command]
+pub async fn ssh_transfer_id() -> Result<u32, Error> {
+    Ok(TRANSFER_ID_MGR.fetch_add(1, Ordering::Release))
+}
+
+#[tauri::command]
+pub async fn ssh_transfer_add(
+    job: TransferJob,
+    wnd: tauri::Window,
+    transfer_mgr: State<'_, TransferMgr>,
+) -> Result<u32, Error> {
+    let id = TRANSFER_ID_MGR.fetch_add(1, Ordering::Release);
+    let mut l = transfer_mgr.lock().await;
+    l.jobs.insert(id, TransferEntry::new(job, wnd));
+    l.schedule();
+    Ok(id)
+}
+
+#[tauri::command]
+pub async fn ssh_transfer_list(
+    transfer_mgr: State<'_, TransferMgr>,
+) -> Result<Vec<TransferInfo>, Error> {
+    let l = transfer_mgr.lock().await;
+    let jobs = l
+        .jobs
+        .iter()
+        .map(|(k, v)| TransferInfo {
+            id: *k,
+            kind: v.job.kind,
+            server_id: v.job.server_id.clone(),
+            local_path: v.job.local_path.clone(),
+            remote_path: v.job.remote_path.clone(),
+            state: v.state,
+            error: v.error.clone(),
+        })
+        .collect();
+    Ok(jobs)
+}
+
+// 运行中的任务由传输循环检查 token 后退出, 状态在退出时更新
+#[tauri::command]
+pub async fn ssh_transfer_cancel(
+    id: u32,
+    transfer_mgr: State<'_, TransferMgr>,
+) -> Result<(), Error> {
+    let mut l = transfer_mgr.lock().await;
+    let v = l.entry_mut(id)?;
+    match v.state {
+        TransferState::Running => v.token.cancel(),
+        TransferState::Queued | TransferState::Paused => {
+            v.set_state(id, TransferState::Cancelled, String::new())
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn ssh_transfer_pause(
+    id: u32,
+    transfer_mgr: State<'_, TransferMgr>,
+) -> Result<(), Error> {
+    let mut l = transfer_mgr.lock().await;
+    let v = l.entry_mut(id)?;
+    match v.state {
+        TransferState::Running => v.token.pause(),
+        TransferState::Queued => v.set_state(id, TransferState::Paused, String::new()),
+        _ => {}
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn ssh_transfer_resume(
+    id: u32,
+    transfer_mgr: State<'_, TransferMgr>,
+) -> Result<(), Error> {
+    let mut l = transfer_mgr.lock().await;
+    let v = l.entry_mut(id)?;
+    if v.state != TransferState::Paused {
+        return Err(anyhow::anyhow!("transfer not paused:{id}").into());
+    }
+
+    v.requeue(id);
+    l.schedule();
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn ssh_transfer_retry(
+    id: u32,
+    transfer_mgr: State<'_, TransferMgr>,
+) -> Result<(), Error> {
+    let mut l = transfer_mgr.lock().await;
+    let v = l.entry_mut(id)?;
+    if !matches!(v.state, TransferState::Failed | TransferState::Cancelled) {
+        return Err(anyhow::anyhow!("transfer not finished:{id}").into());
+    }
+
+    v.requeue(id);
+    l.schedule();
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn ssh_transfer_set_concurrency(
+    concurrency: usize,
+    transfer_mgr: State<'_, TransferMgr>,
+) -> Result<(), Error> {
+    let mut l = transfer_mgr.lock().await;
+    l.concurrency = concurrency.max(1);
+    l.schedule();
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn ssh_transfer_remove(
+    id: u32,
+    transfer_mgr: State<'_, TransferMgr>,
+) -> Result<(), Error> {
+    let mut l = transfer_mgr.lock().await;
+    if !l.entry_mut(id)?.finished() {
+        return Err(anyhow::anyhow!("transfer not finished:{id}").into());
+    }
+
+    l.jobs.remove(&id);
+    Ok(())
+}
+
+// 移除所有已完成, 失败和取消的任务
+#[tauri::command]
+pub async fn ssh_transfer_clear(transfer_mgr: State<'_, TransferMgr>) -> Result<(), Error> {
+    transfer_mgr.lock().await.jobs.retain(|_, v| !v.finished());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requeue_options() {
+        let user = TransferOptions::default();
+        let v = run_options(&user, Resume::No);
+        assert!(!v.resume && !v.verify);
+        let v = run_options(&user, Resume::Trusted);
+        assert!(v.resume && !v.verify);
+        let v = run_options(&user, Resume::Verify);
+        assert!(v.resume && v.verify);
+
+        // 用户开启续传但不校验时, 重试保持用户的选项
+        let user = TransferOptions {
+            resume: true,
+            ..Default::default()
+        };
+        let v = run_options(&user, Resume::Verify);
+        assert!(v.resume && !v.verify);
+        assert!(user.resume && !user.verify);
+    }
+}
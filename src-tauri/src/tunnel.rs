@@ -0,0 +1,665 @@
+use crate::{
+    pool::{session_acquire, PooledSession, SessionPool},
+    prompt::{AuthPrompt, PromptMgr, PromptSink},
+    proxy::{
+        SOCKS5_ATYP_DOMAIN, SOCKS5_ATYP_IPV4, SOCKS5_ATYP_IPV6, SOCKS5_AUTH_NONE,
+        SOCKS5_AUTH_REJECT, SOCKS5_CMD_CONNECT, SOCKS5_VER,
+    },
+    server::ServerContext,
+    ssh::{into_essh, Error},
+};
+use anyhow::Result;
+use async_ssh2_lite::{AsyncChannel, AsyncListener, AsyncSession, TokioTcpStream};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU16, AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tauri::{async_runtime::Mutex, Emitter, Manager, State};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpListener,
+    sync::watch,
+};
+
+pub const ENT_TUNNEL: &str = "tauri://TunnelMessage";
+
+static TUNNEL_ID_MGR: AtomicU32 = AtomicU32::new(1);
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+// accept 持续失败 (如 EMFILE) 时等待后重试, 连续失败过多则结束隧道
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(200);
+const ACCEPT_MAX_ERRORS: u32 = 50;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TunnelKind {
+    // -L 本地端口转发到远端
+    #[default]
+    Local,
+    // -R 远端端口转发到本地, bind 为服务器上的监听地址
+    Remote,
+    // -D 本地 SOCKS5 代理, 无需 target
+    Dynamic,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TunnelStatus {
+    #[default]
+    Running,
+    Stopped,
+    Failed,
+    Connecting,
+    Retrying,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TunnelSpec {
+    #[serde(default)]
+    pub kind: TunnelKind,
+    #[serde(default = "TunnelSpec::default_bind_host")]
+    pub bind_host: String,
+    pub bind_port: u16,
+    #[serde(default)]
+    pub target_host: String,
+    #[serde(default)]
+    pub target_port: u16,
+    // 保存在服务器配置中, 登录后自动启动
+    #[serde(default)]
+    pub auto_start: bool,
+}
+
+impl TunnelSpec {
+    fn default_bind_host() -> String {
+        String::from("127.0.0.1")
+    }
+}
+
+#[derive(Default)]
+pub struct TunnelStats {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    active: AtomicU32,
+    total: AtomicU64,
+    bind_port: AtomicU16,
+    status: std::sync::Mutex<(TunnelStatus, String)>,
+}
+
+impl TunnelStats {
+    fn set_status(&self, status: TunnelStatus, error: String) {
+        *self.status.lock().unwrap_or_else(|e| e.into_inner()) = (status, error);
+    }
+
+    fn status(&self) -> (TunnelStatus, String) {
+        self.status
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+pub struct TunnelContext {
+    server_id: u32,
+    server_name: String,
+    spec: TunnelSpec,
+    stats: Arc<TunnelStats>,
+    tx: watch::Sender<bool>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TunnelInfo {
+    id: u32,
+    server_id: String,
+    server_name: String,
+    #[serde(flatten)]
+    spec: TunnelSpec,
+    bytes_in: u64,
+    bytes_out: u64,
+    active_connections: u32,
+    total_connections: u64,
+    status: TunnelStatus,
+    error: String,
+}
+
+pub type TunnelMgr = Mutex<HashMap<u32, TunnelContext>>;
+
+// 统计本地连接的收发字节数: 读为发往远端, 写为从远端收到
+struct Counted<T> {
+    inner: T,
+    stats: Arc<TunnelStats>,
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Counted<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let ret = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let n = buf.filled().len() - before;
+        self.stats.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+        ret
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Counted<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let ret = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = ret {
+            self.stats.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        ret
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+async fn pipe(
+    sock: TokioTcpStream,
+    mut channel: AsyncChannel<TokioTcpStream>,
+    stats: Arc<TunnelStats>,
+    mut stop: watch::Receiver<bool>,
+) {
+    sock.set_nodelay(true).ok();
+
+    let mut local = Counted { inner: sock, stats };
+    tokio::select! {
+        _ = stop.changed() => {}
+        _ = tokio::io::copy_bidirectional(&mut local, &mut channel) => {}
+    }
+
+    channel.close().await.ok();
+}
+
+async fn forward_local(
+    session: AsyncSession<TokioTcpStream>,
+    sock: TokioTcpStream,
+    peer: SocketAddr,
+    spec: TunnelSpec,
+    stats: Arc<TunnelStats>,
+    stop: watch::Receiver<bool>,
+) -> Result<()> {
+    let src = peer.ip().to_string();
+    let channel = session
+        .channel_direct_tcpip(
+            &spec.target_host,
+            spec.target_port,
+            Some((&src, peer.port())),
+        )
+        .await?;
+
+    pipe(sock, channel, stats, stop).await;
+    Ok(())
+}
+
+async fn forward_remote(
+    channel: AsyncChannel<TokioTcpStream>,
+    spec: TunnelSpec,
+    stats: Arc<TunnelStats>,
+    stop: watch::Receiver<bool>,
+) -> Result<()> {
+    let sock = TokioTcpStream::connect((spec.target_host.as_str(), spec.target_port)).await?;
+    pipe(sock, channel, stats, stop).await;
+    Ok(())
+}
+
+// SOCKS5 服务端握手, 仅支持无认证的 CONNECT, 返回目标地址
+async fn socks5_accept(sock: &mut TokioTcpStream) -> Result<(String, u16)> {
+    let mut head = [0u8; 2];
+    sock.read_exact(&mut head).await?;
+    if head[0] != SOCKS5_VER {
+        anyhow::bail!("socks5 invalid version:{}", head[0]);
+    }
+
+    let mut methods = vec![0u8; head[1] as usize];
+    sock.read_exact(&mut methods).await?;
+    if !methods.contains(&SOCKS5_AUTH_NONE) {
+        sock.write_all(&[SOCKS5_VER, SOCKS5_AUTH_REJECT]).await?;
+        anyhow::bail!("socks5 no acceptable auth method");
+    }
+    sock.write_all(&[SOCKS5_VER, SOCKS5_AUTH_NONE]).await?;
+
+    let mut req = [0u8; 4];
+    sock.read_exact(&mut req).await?;
+    if req[1] != SOCKS5_CMD_CONNECT {
+        socks5_reply(sock, 0x07).await?;
+        anyhow::bail!("socks5 unsupported command:{}", req[1]);
+    }
+
+    let host = match req[3] {
+        SOCKS5_ATYP_IPV4 => {
+            let mut ip = [0u8; 4];
+            sock.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        SOCKS5_ATYP_IPV6 => {
+            let mut ip = [0u8; 16];
+            sock.read_exact(&mut ip).await?;
+            Ipv6Addr::from(ip).to_string()
+        }
+        SOCKS5_ATYP_DOMAIN => {
+            let mut name = vec![0u8; sock.read_u8().await? as usize];
+            sock.read_exact(&mut name).await?;
+            String::from_utf8(name)?
+        }
+        v => {
+            socks5_reply(sock, 0x08).await?;
+            anyhow::bail!("socks5 invalid address type:{v}");
+        }
+    };
+    let port = sock.read_u16().await?;
+
+    Ok((host, port))
+}
+
+async fn socks5_reply(sock: &mut TokioTcpStream, code: u8) -> Result<()> {
+    sock.write_all(&[SOCKS5_VER, code, 0, SOCKS5_ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+        .await?;
+    Ok(())
+}
+
+async fn forward_dynamic(
+    session: AsyncSession<TokioTcpStream>,
+    mut sock: TokioTcpStream,
+    peer: SocketAddr,
+    stats: Arc<TunnelStats>,
+    stop: watch::Receiver<bool>,
+) -> Result<()> {
+    let (host, port) = socks5_accept(&mut sock).await?;
+
+    let src = peer.ip().to_string();
+    let ret = session
+        .channel_direct_tcpip(&host, port, Some((&src, peer.port())))
+        .await;
+
+    let channel = match ret {
+        Ok(v) => v,
+        Err(e) => {
+            socks5_reply(&mut sock, 0x01).await.ok();
+            return Err(e.into());
+        }
+    };
+
+    socks5_reply(&mut sock, 0x00).await?;
+    pipe(sock, channel, stats, stop).await;
+    Ok(())
+}
+
+// 会话断开时返回
+async fn session_closed(session: &AsyncSession<TokioTcpStream>) {
+    loop {
+        tokio::time::sleep(KEEPALIVE_INTERVAL).await;
+        if session.keepalive_send().await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn run_local(
+    session: AsyncSession<TokioTcpStream>,
+    listener: TcpListener,
+    spec: TunnelSpec,
+    stats: Arc<TunnelStats>,
+    mut stop: watch::Receiver<bool>,
+) -> Result<()> {
+    let closed = session_closed(&session);
+    tokio::pin!(closed);
+    let mut errors = 0;
+
+    loop {
+        let (sock, peer) = tokio::select! {
+            _ = stop.changed() => return Ok(()),
+            _ = &mut closed => anyhow::bail!("session closed"),
+            ret = listener.accept() => match ret {
+                Ok(v) => v,
+                Err(e) => {
+                    errors += 1;
+                    if errors >= ACCEPT_MAX_ERRORS {
+                        anyhow::bail!("accept failed: {e}");
+                    }
+                    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            },
+        };
+        errors = 0;
+
+        stats.total.fetch_add(1, Ordering::Relaxed);
+        stats.active.fetch_add(1, Ordering::Relaxed);
+
+        let (session, spec, stats, stop) =
+            (session.clone(), spec.clone(), stats.clone(), stop.clone());
+        tauri::async_runtime::spawn(async move {
+            let ret = match spec.kind {
+                TunnelKind::Dynamic => {
+                    forward_dynamic(session, sock, peer, stats.clone(), stop).await
+                }
+                _ => forward_local(session, sock, peer, spec, stats.clone(), stop).await,
+            };
+            ret.ok();
+            stats.active.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+}
+
+async fn run_remote(
+    session: AsyncSession<TokioTcpStream>,
+    mut listener: AsyncListener<TokioTcpStream>,
+    spec: TunnelSpec,
+    stats: Arc<TunnelStats>,
+    mut stop: watch::Receiver<bool>,
+) -> Result<()> {
+    let closed = session_closed(&session);
+    tokio::pin!(closed);
+
+    // listener 释放时取消服务器上的端口监听
+    loop {
+        let channel = tokio::select! {
+            _ = stop.changed() => return Ok(()),
+            _ = &mut closed => anyhow::bail!("session closed"),
+            ret = listener.accept() => ret?,
+        };
+
+        stats.total.fetch_add(1, Ordering::Relaxed);
+        stats.active.fetch_add(1, Ordering::Relaxed);
+
+        let (spec, stats, stop) = (spec.clone(), stats.clone(), stop.clone());
+        tauri::async_runtime::spawn(async move {
+            forward_remote(channel, spec, stats.clone(), stop)
+                .await
+                .ok();
+            stats.active.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+}
+
+enum TunnelListener {
+    Local(TcpListener),
+    Remote(AsyncListener<TokioTcpStream>),
+}
+
+// 建立会话并监听, 返回实际监听的端口
+async fn tunnel_connect(
+    wnd: &tauri::Window,
+    server_id: u32,
+    spec: &TunnelSpec,
+) -> Result<(PooledSession, TunnelListener, u16)> {
+    let info = wnd
+        .state::<ServerContext>()
+        .lock()
+        .await
+        .connect_info(server_id)?;
+
+    let prompt_mgr = wnd.state::<PromptMgr>();
+    let prompt = AuthPrompt {
+        mgr: &prompt_mgr,
+        sink: PromptSink::Window(wnd.clone()),
+    };
+    let pool = wnd.state::<SessionPool>();
+    let session = session_acquire(&pool, server_id, &info, &prompt).await?;
+
+    match spec.kind {
+        TunnelKind::Local | TunnelKind::Dynamic => {
+            let listener = TcpListener::bind((spec.bind_host.as_str(), spec.bind_port)).await?;
+            let port = listener.local_addr()?.port();
+            Ok((session, TunnelListener::Local(listener), port))
+        }
+        TunnelKind::Remote => {
+            let (listener, port) = session
+                .channel_forward_listen(spec.bind_port, Some(&spec.bind_host), None)
+                .await?;
+            Ok((session, TunnelListener::Remote(listener), port))
+        }
+    }
+}
+
+async fn tunnel_serve(
+    session: PooledSession,
+    listener: TunnelListener,
+    spec: TunnelSpec,
+    stats: Arc<TunnelStats>,
+    stop: watch::Receiver<bool>,
+) -> Result<()> {
+    let inner = (*session).clone();
+    let ret = match listener {
+        TunnelListener::Local(v) => run_local(inner, v, spec, stats, stop).await,
+        TunnelListener::Remote(v) => run_remote(inner, v, spec, stats, stop).await,
+    };
+
+    // 出错时会话可能已断开, 重试时重新建立
+    if ret.is_err() {
+        session.invalidate();
+    }
+    ret
+}
+
+fn tunnel_notify(
+    wnd: &tauri::Window,
+    id: u32,
+    stats: &TunnelStats,
+    status: TunnelStatus,
+    error: String,
+    attempt: u32,
+) {
+    wnd.emit(
+        ENT_TUNNEL,
+        json!({
+            "id": id,
+            "status": status,
+            "error": error,
+            "attempt": attempt,
+        }),
+    )
+    .ok();
+    stats.set_status(status, error);
+}
+
+// 隧道断开后按重连策略重建会话, 自动启动的隧道总是重试
+async fn tunnel_supervise(
+    id: u32,
+    server_id: u32,
+    mut spec: TunnelSpec,
+    mut conn: Option<(PooledSession, TunnelListener)>,
+    stats: Arc<TunnelStats>,
+    mut stop: watch::Receiver<bool>,
+    wnd: tauri::Window,
+) {
+    let policy = wnd
+        .state::<ServerContext>()
+        .lock()
+        .await
+        .config
+        .reconnect
+        .clone();
+    let mut attempt = 0;
+
+    loop {
+        let ret = match conn.take() {
+            Some(v) => Ok(v),
+            None => tokio::select! {
+                _ = stop.changed() => break,
+                ret = tunnel_connect(&wnd, server_id, &spec) => ret.map(|(session, listener, port)| {
+                    // 重连时沿用首次监听的端口
+                    spec.bind_port = port;
+                    stats.bind_port.store(port, Ordering::Relaxed);
+                    (session, listener)
+                }),
+            },
+        };
+
+        let ret = match ret {
+            Ok((session, listener)) => {
+                attempt = 0;
+                tunnel_notify(&wnd, id, &stats, TunnelStatus::Running, String::new(), 0);
+                tunnel_serve(session, listener, spec.clone(), stats.clone(), stop.clone()).await
+            }
+            Err(e) => Err(e),
+        };
+
+        let error = match ret {
+            Ok(_) => break,
+            Err(e) => e.to_string(),
+        };
+
+        attempt += 1;
+        if !(policy.enabled || spec.auto_start) || attempt > policy.max_attempts {
+            tunnel_notify(&wnd, id, &stats, TunnelStatus::Failed, error, attempt);
+            return;
+        }
+
+        tunnel_notify(&wnd, id, &stats, TunnelStatus::Retrying, error, attempt);
+        tokio::select! {
+            _ = stop.changed() => break,
+            _ = tokio::time::sleep(policy.delay(attempt)) => {}
+        }
+    }
+
+    tunnel_notify(&wnd, id, &stats, TunnelStatus::Stopped, String::new(), 0);
+}
+
+fn tunnel_register(
+    server_id: u32,
+    server_name: String,
+    spec: TunnelSpec,
+    tunnels: &mut HashMap<u32, TunnelContext>,
+) -> (u32, Arc<TunnelStats>, watch::Receiver<bool>) {
+    let (tx, rx) = watch::channel(false);
+    let stats = Arc::new(TunnelStats::default());
+    stats.bind_port.store(spec.bind_port, Ordering::Relaxed);
+
+    let tunnel_id = TUNNEL_ID_MGR.fetch_add(1, Ordering::Release);
+    tunnels.insert(
+        tunnel_id,
+        TunnelContext {
+            server_id,
+            server_name,
+            spec,
+            stats: stats.clone(),
+            tx,
+        },
+    );
+
+    (tunnel_id, stats, rx)
+}
+
+// 登录后启动服务器上保存的自动启动隧道, 已在运行的跳过, 已失败的替换为新的隧道
+pub async fn tunnel_autostart(
+    wnd: &tauri::Window,
+    tunnel_mgr: &TunnelMgr,
+    tunnels: Vec<(u32, String, TunnelSpec)>,
+) {
+    let mut l = tunnel_mgr.lock().await;
+
+    for (server_id, server_name, spec) in tunnels {
+        let same = |v: &TunnelContext| v.server_id == server_id && v.spec == spec;
+        if l.values()
+            .any(|v| same(v) && v.stats.status().0 != TunnelStatus::Failed)
+        {
+            continue;
+        }
+        l.retain(|_, v| !same(v));
+
+        let (id, stats, rx) = tunnel_register(server_id, server_name, spec.clone(), &mut l);
+        stats.set_status(TunnelStatus::Connecting, String::new());
+        tauri::async_runtime::spawn(tunnel_supervise(
+            id,
+            server_id,
+            spec,
+            None,
+            stats,
+            rx,
+            wnd.clone(),
+        ));
+    }
+}
+
+#[tauri::command]
+pub async fn ssh_tunnel_start(
+    id: String,
+    spec: TunnelSpec,
+    wnd: tauri::Window,
+    tunnel_mgr: State<'_, TunnelMgr>,
+    svr_ctx: State<'_, ServerContext>,
+) -> Result<u32, Error> {
+    let id_key = id.parse::<u32>().map_err(into_essh)?;
+    let server_name = svr_ctx.lock().await.connect_info(id_key)?.server.name;
+
+    // 首次连接失败直接返回错误, 不进入重试
+    let (session, listener, port) = tunnel_connect(&wnd, id_key, &spec).await?;
+
+    let (tunnel_id, stats, rx) = tunnel_register(
+        id_key,
+        server_name,
+        spec.clone(),
+        &mut *tunnel_mgr.lock().await,
+    );
+    stats.bind_port.store(port, Ordering::Relaxed);
+
+    let mut spec = spec;
+    spec.bind_port = port;
+    tauri::async_runtime::spawn(tunnel_supervise(
+        tunnel_id,
+        id_key,
+        spec,
+        Some((session, listener)),
+        stats,
+        rx,
+        wnd,
+    ));
+
+    Ok(tunnel_id)
+}
+
+#[tauri::command]
+pub async fn ssh_tunnel_stop(id: u32, tunnel_mgr: State<'_, TunnelMgr>) -> Result<(), Error> {
+    if let Some(v) = tunnel_mgr.lock().await.remove(&id) {
+        v.tx.send(true).ok();
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn ssh_tunnel_list(tunnel_mgr: State<'_, TunnelMgr>) -> Result<Vec<TunnelInfo>, Error> {
+    let l = tunnel_mgr.lock().await;
+    let mut tunnels: Vec<TunnelInfo> = l
+        .iter()
+        .map(|(k, v)| (k, v, v.stats.status()))
+        .map(|(k, v, (status, error))| TunnelInfo {
+            id: *k,
+            server_id: v.server_id.to_string(),
+            server_name: v.server_name.clone(),
+            spec: TunnelSpec {
+                bind_port: v.stats.bind_port.load(Ordering::Relaxed),
+                ..v.spec.clone()
+            },
+            bytes_in: v.stats.bytes_in.load(Ordering::Relaxed),
+            bytes_out: v.stats.bytes_out.load(Ordering::Relaxed),
+            active_connections: v.stats.active.load(Ordering::Relaxed),
+            total_connections: v.stats.total.load(Ordering::Relaxed),
+            status,
+            error,
+        })
+        .collect();
+
+    tunnels.sort_by_key(|v| v.id);
+    Ok(tunnels)
+}
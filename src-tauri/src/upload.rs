@@ -1,130 +1,662 @@
 use crate::{
+    exec::{exec_command, ExecOptions},
+    pool::{session_acquire, PooledSession, SessionPool},
+    prompt::{AuthPrompt, PromptMgr, PromptSink},
     server::ServerContext,
-    ssh::{into_essh, ssh_create_session, Error},
+    ssh::{into_essh, Error},
+    transfer::{transfer_wait, CancelToken, TransferJob, TransferKind, TRANSFER_ID_MGR},
 };
 use anyhow::Result;
-use async_ssh2_lite::{AsyncSftp, TokioTcpStream};
-use futures_util::AsyncWriteExt;
+use async_ssh2_lite::{
+    ssh2::{OpenFlags, OpenType},
+    AsyncSession, AsyncSftp, TokioTcpStream,
+};
+use futures_util::{AsyncSeekExt, AsyncWriteExt};
+use openssl::sha::Sha256;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::{fs::Metadata, path::Path, time::Instant};
-use tauri::{Emitter, State};
-use tokio::io::AsyncReadExt;
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+use tauri::{Emitter, Manager};
+use tokio::io::{AsyncReadExt, AsyncSeekExt as _};
 
 pub const ENT_FTM: &str = "tauri://FileTransferMessage";
 
+// 进度事件最短间隔, 最后一块数据总会发送
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+// 服务器上计算已传部分哈希的超时, 毫秒
+const HASH_TIMEOUT: u64 = 30 * 60 * 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferPhase {
+    Connecting,
+    Transferring,
+    Verifying,
+    Done,
+}
+
 #[tauri::command]
 pub async fn ssh_upload(
     id: String,
     local_path: String,
     remote_path: String,
+    options: Option<TransferOptions>,
+    transfer_id: Option<u32>,
     wnd: tauri::Window,
-    svr_ctx: State<'_, ServerContext>,
 ) -> Result<(), Error> {
     //println!("ssh_upload id:{id}, local_path:{local_path}, remote_path:{remote_path}");
-    let id_key = id.parse::<u32>().map_err(into_essh)?;
-    let lsm = svr_ctx.lock().await;
+    let job = TransferJob {
+        kind: TransferKind::Upload,
+        server_id: id,
+        local_path,
+        remote_path,
+        options: options.unwrap_or_default(),
+    };
+    // 前端通过 ssh_transfer_id 预先取得 id, 用于过滤进度消息和暂停、取消
+    let transfer_id =
+        transfer_id.unwrap_or_else(|| TRANSFER_ID_MGR.fetch_add(1, Ordering::Release));
+    transfer_wait(&wnd, transfer_id, job)
+        .await
+        .map_err(into_essh)
+}
 
-    let cfg = lsm.config.clone();
-    let server = lsm
-        .servers
-        .get(&id_key)
-        .ok_or(into_essh(anyhow::anyhow!("server not found")))?
-        .clone();
+// 建立 sftp, 返回的会话需要在传输期间保持
+pub async fn transfer_connect(
+    wnd: &tauri::Window,
+    transfer_id: u32,
+    id_key: u32,
+) -> Result<(PooledSession, AsyncSftp<TokioTcpStream>)> {
+    let info = wnd
+        .state::<ServerContext>()
+        .lock()
+        .await
+        .connect_info(id_key)?;
+    let server = &info.server;
 
-    drop(lsm);
+    let message = format!("正在链接:{}:{}", server.host, server.port);
+    emit_connecting(wnd, transfer_id, &message);
 
-    let json_d1 = json!({
-        "rate": 0,
-        "message": format!("正在链接:{}:{}", server.host, server.port),
-    });
-    wnd.emit(ENT_FTM, json_d1).ok();
+    let prompt_mgr = wnd.state::<PromptMgr>();
+    let prompt = AuthPrompt {
+        mgr: &prompt_mgr,
+        sink: PromptSink::Window(wnd.clone()),
+    };
+    let pool = wnd.state::<SessionPool>();
+    let session = session_acquire(&pool, id_key, &info, &prompt).await?;
+    let sftp = session.sftp().await?;
+
+    emit_connecting(wnd, transfer_id, "链接成功");
+
+    Ok((session, sftp))
+}
+
+pub async fn upload_start(
+    wnd: &tauri::Window,
+    transfer_id: u32,
+    id: &str,
+    local_path: &str,
+    remote_path: &str,
+    options: &TransferOptions,
+) -> Result<()> {
+    let id_key = id.parse::<u32>()?;
+    let (session, sftp) = transfer_connect(wnd, transfer_id, id_key).await?;
+    upload_files(
+        wnd,
+        transfer_id,
+        &session,
+        sftp,
+        local_path,
+        remote_path,
+        options,
+    )
+    .await
+}
+
+// 目录传输中单个文件出错时的处理方式
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorPolicy {
+    #[default]
+    Abort,
+    Continue,
+}
+
+// 下载目录时远端符号链接的处理方式
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkPolicy {
+    #[default]
+    Follow,
+    Skip,
+    // 在本地创建指向相同目标的链接
+    Link,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct TransferOptions {
+    #[serde(default)]
+    pub on_error: ErrorPolicy,
+    #[serde(default)]
+    pub symlinks: SymlinkPolicy,
+    // 目标不大于源文件且不早于源文件修改(或开启校验)时从断点继续
+    #[serde(default)]
+    pub resume: bool,
+    // 续传前校验已传部分的 sha256, 不一致时重新传输
+    #[serde(default)]
+    pub verify: bool,
+    // 由传输队列设置, 单独调用时不会被取消
+    #[serde(skip)]
+    pub token: CancelToken,
+}
+
+// 参数为 (大小, 修改时间秒), 返回续传位置和是否需要校验已传部分.
+// 不校验时只接受修改时间不早于源文件的目标, 避免接在无关的文件后面
+pub fn resume_offset(src: (u64, u64), dst: Option<(u64, u64)>, verify: bool) -> (u64, bool) {
+    match dst {
+        Some((len, _)) if len == 0 || len > src.0 => (0, false),
+        Some((len, _)) if verify => (len, true),
+        Some((len, mtime)) if mtime >= src.1 => (len, false),
+        _ => (0, false),
+    }
+}
+
+pub fn local_mtime(meta: &std::fs::Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|v| v.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |v| v.as_secs())
+}
+
+pub async fn hash_local(path: &Path, len: u64) -> Result<[u8; 32]> {
+    let mut file = tokio::fs::File::open(path).await?.take(len);
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 256 * 1024];
+
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finish())
+}
+
+fn shell_quote(v: &str) -> String {
+    format!("'{}'", v.replace('\'', "'\\''"))
+}
+
+fn parse_sha256(out: &str) -> Option<[u8; 32]> {
+    let hex = out.split_whitespace().next()?;
+    if hex.len() != 64 {
+        return None;
+    }
+
+    let mut hash = [0u8; 32];
+    for (i, v) in hash.iter_mut().enumerate() {
+        *v = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(hash)
+}
+
+async fn hash_remote_exec(
+    session: &AsyncSession<TokioTcpStream>,
+    path: &Path,
+    len: u64,
+) -> Option<[u8; 32]> {
+    let path = shell_quote(&path.to_string_lossy());
+    let command = format!("head -c {len} -- {path} | sha256sum");
+    let options = ExecOptions {
+        timeout: HASH_TIMEOUT,
+        ..Default::default()
+    };
+
+    let ret = exec_command(session, &command, &options).await.ok()?;
+    match ret.exit_status {
+        0 => parse_sha256(&ret.stdout),
+        _ => None,
+    }
+}
+
+// 优先在服务器上计算, 避免为校验把已传部分再读一遍; 无法执行命令时通过 sftp 读取
+pub async fn hash_remote(
+    session: &AsyncSession<TokioTcpStream>,
+    sftp: &AsyncSftp<TokioTcpStream>,
+    path: &Path,
+    len: u64,
+) -> Result<[u8; 32]> {
+    if let Some(v) = hash_remote_exec(session, path, len).await {
+        return Ok(v);
+    }
 
-    let session = ssh_create_session(&server, &cfg).await.map_err(into_essh)?;
-    let sftp = session.sftp().await.map_err(into_essh)?;
+    let mut file = futures_util::AsyncReadExt::take(sftp.open(path).await?, len);
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 256 * 1024];
 
-    let json_d2 = json!({
+    loop {
+        let n = futures_util::AsyncReadExt::read(&mut file, &mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finish())
+}
+
+fn emit_connecting(wnd: &tauri::Window, transfer_id: u32, message: &str) {
+    let json_data = json!({
+        "id": transfer_id,
+        "phase": TransferPhase::Connecting,
         "rate": 0,
-        "message": "链接成功",
+        "message": message,
     });
-    wnd.emit(ENT_FTM, json_d2).ok();
+    wnd.emit(ENT_FTM, json_data).ok();
+}
 
-    upload_files(&wnd, sftp, &local_path, &remote_path)
-        .await
-        .map_err(into_essh)?;
+// 按整体字节数汇总进度, rate/message 保留给旧界面使用
+pub struct Progress {
+    id: u32,
+    total_files: usize,
+    total_size: u64,
+    done_files: usize,
+    now_size: u64,
+    // 续传跳过的字节不计入速度
+    skipped: u64,
+    start: Instant,
+    last_time: Instant,
+    last_size: u64,
+    speed: u64,
+}
+
+impl Progress {
+    pub fn new(id: u32, total_files: usize, total_size: u64) -> Self {
+        let now = Instant::now();
+        Self {
+            id,
+            total_files,
+            total_size,
+            done_files: 0,
+            now_size: 0,
+            skipped: 0,
+            start: now,
+            last_time: now,
+            last_size: 0,
+            speed: 0,
+        }
+    }
+
+    fn rate(&self) -> u64 {
+        match self.total_size {
+            0 => 0,
+            v => self.now_size * 100 / v,
+        }
+    }
+
+    fn emit(&mut self, wnd: &tauri::Window, phase: TransferPhase, file: &str, message: String) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_time).as_secs_f64();
+        if elapsed > 0.0 {
+            self.speed = ((self.now_size - self.last_size) as f64 / elapsed) as u64;
+        }
+        self.last_time = now;
+        self.last_size = self.now_size;
+
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let avg_speed = match elapsed > 0.0 {
+            true => ((self.now_size - self.skipped) as f64 / elapsed) as u64,
+            false => 0,
+        };
+        let eta = match avg_speed {
+            0 => None,
+            v => Some(self.total_size.saturating_sub(self.now_size) / v),
+        };
+
+        let json_data = json!({
+            "id": self.id,
+            "phase": phase,
+            "rate": match phase {
+                TransferPhase::Done => 100,
+                _ => self.rate(),
+            },
+            "message": message,
+            "file": file,
+            "done_files": self.done_files,
+            "total_files": self.total_files,
+            "done_bytes": self.now_size,
+            "total_bytes": self.total_size,
+            "speed": self.speed,
+            "avg_speed": avg_speed,
+            "eta": eta,
+        });
+        wnd.emit(ENT_FTM, json_data).ok();
+    }
+
+    pub fn skip(&mut self, n: u64) {
+        self.now_size += n;
+        self.skipped += n;
+        self.last_size += n;
+    }
+
+    pub fn advance(&mut self, wnd: &tauri::Window, n: u64, file_name: &str) {
+        self.now_size += n;
+        if self.last_time.elapsed() < PROGRESS_INTERVAL && self.now_size < self.total_size {
+            return;
+        }
+
+        let message = match self.total_files {
+            1 => file_name.to_string(),
+            _ => format!(
+                "[{}/{}] {}",
+                self.done_files + 1,
+                self.total_files,
+                file_name
+            ),
+        };
+        self.emit(wnd, TransferPhase::Transferring, file_name, message);
+    }
+
+    pub fn verifying(&mut self, wnd: &tauri::Window, file_name: &str) {
+        let message = format!("正在校验:{file_name}");
+        self.emit(wnd, TransferPhase::Verifying, file_name, message);
+    }
+
+    pub fn file_done(&mut self) {
+        self.done_files += 1;
+    }
+
+    pub fn finish(&mut self, wnd: &tauri::Window, name: &str, time: Instant) {
+        let message = match self.total_files {
+            1 => format!(
+                "{}, time:{} ms, size:{}",
+                name,
+                time.elapsed().as_millis(),
+                self.total_size
+            ),
+            _ => format!(
+                "{}, files:{}/{}, time:{} ms, size:{}",
+                name,
+                self.done_files,
+                self.total_files,
+                time.elapsed().as_millis(),
+                self.now_size
+            ),
+        };
+        self.emit(wnd, TransferPhase::Done, name, message);
+    }
+}
+
+// 远端路径固定使用 '/', 不受本地系统影响
+pub fn remote_join(base: &Path, name: &str) -> PathBuf {
+    let base = base.to_string_lossy();
+    PathBuf::from(format!("{}/{}", base.trim_end_matches('/'), name))
+}
+
+// 返回相对路径的目录列表和文件列表 (本地路径, 相对路径, 大小),
+// 单个条目出错按 on_error 处理, 指向目录的符号链接不进入以免循环
+async fn walk_local(
+    root: &Path,
+    on_error: ErrorPolicy,
+    failed: &mut Vec<String>,
+) -> Result<(Vec<String>, Vec<(PathBuf, String, u64)>)> {
+    let mut dirs = Vec::new();
+    let mut files = Vec::new();
+    let mut stack = vec![(root.to_path_buf(), String::new())];
+
+    let mut fail = |name: &str, e: std::io::Error| -> Result<()> {
+        match on_error {
+            ErrorPolicy::Abort => Err(e.into()),
+            ErrorPolicy::Continue => {
+                failed.push(format!("{name}: {e}"));
+                Ok(())
+            }
+        }
+    };
+
+    while let Some((dir, rel)) = stack.pop() {
+        let mut rd = match tokio::fs::read_dir(&dir).await {
+            Ok(v) => v,
+            Err(e) => {
+                fail(&dir.to_string_lossy(), e)?;
+                continue;
+            }
+        };
+
+        loop {
+            let entry = match rd.next_entry().await {
+                Ok(Some(v)) => v,
+                Ok(None) => break,
+                Err(e) => {
+                    fail(&dir.to_string_lossy(), e)?;
+                    break;
+                }
+            };
+
+            let name = entry.file_name().to_string_lossy().to_string();
+            let rel_path = match rel.is_empty() {
+                true => name,
+                false => format!("{rel}/{name}"),
+            };
+
+            let mut ft = match tokio::fs::symlink_metadata(entry.path()).await {
+                Ok(v) => v,
+                Err(e) => {
+                    fail(&rel_path, e)?;
+                    continue;
+                }
+            };
+            if ft.is_symlink() {
+                // 失效的链接按出错处理
+                ft = match tokio::fs::metadata(entry.path()).await {
+                    Ok(v) => v,
+                    Err(e) => {
+                        fail(&rel_path, e)?;
+                        continue;
+                    }
+                };
+                if ft.is_dir() {
+                    continue;
+                }
+            }
+
+            if ft.is_dir() {
+                dirs.push(rel_path.clone());
+                stack.push((entry.path(), rel_path));
+            } else {
+                files.push((entry.path(), rel_path, ft.len()));
+            }
+        }
+    }
+
+    dirs.sort();
+    Ok((dirs, files))
+}
+
+async fn remote_mkdir(sftp: &AsyncSftp<TokioTcpStream>, path: &Path) -> Result<()> {
+    if sftp.stat(path).await.is_ok_and(|v| v.is_dir()) {
+        return Ok(());
+    }
+    sftp.mkdir(path, 0o755).await?;
     Ok(())
 }
 
 async fn upload_files<P: AsRef<Path>>(
     wnd: &tauri::Window,
+    transfer_id: u32,
+    session: &AsyncSession<TokioTcpStream>,
     sftp: AsyncSftp<TokioTcpStream>,
     local: P,
     remote: P,
+    options: &TransferOptions,
 ) -> Result<()> {
+    let time = Instant::now();
     let local_path = local.as_ref();
     let remote_path = remote.as_ref();
 
     let ft = tokio::fs::metadata(local_path).await?;
+    let name = local_path.file_name().unwrap_or_default().to_string_lossy();
+    let remote_root = remote_join(remote_path, &name);
 
-    if ft.is_dir() {
-        anyhow::bail!("local_path is dir");
-    } else {
-        upload_onefile(wnd, &sftp, local_path, remote_path, &ft).await?;
+    if !ft.is_dir() {
+        let mut progress = Progress::new(transfer_id, 1, ft.len());
+        upload_onefile(
+            wnd,
+            session,
+            &sftp,
+            local_path,
+            &remote_root,
+            options,
+            &mut progress,
+        )
+        .await?;
+        progress.file_done();
+        progress.finish(wnd, &name, time);
+        return Ok(());
     }
 
+    let mut failed: Vec<String> = Vec::new();
+    let (dirs, files) = walk_local(local_path, options.on_error, &mut failed).await?;
+    let total_size = files.iter().map(|v| v.2).sum();
+    let mut progress = Progress::new(transfer_id, files.len(), total_size);
+
+    // 先建目录, 目录失败时其下的文件也会失败并按策略处理
+    remote_mkdir(&sftp, &remote_root).await?;
+    for d in dirs.iter() {
+        if let Err(e) = remote_mkdir(&sftp, &remote_join(&remote_root, d)).await {
+            match options.on_error {
+                ErrorPolicy::Abort => return Err(e),
+                ErrorPolicy::Continue => failed.push(format!("{d}: {e}")),
+            }
+        }
+    }
+
+    for (src, rel, _) in files.iter() {
+        options.token.check()?;
+        let dst = remote_join(&remote_root, rel);
+        match upload_onefile(wnd, session, &sftp, src, &dst, options, &mut progress).await {
+            Ok(_) => progress.file_done(),
+            // 被取消或暂停时不按失败文件处理
+            Err(e)
+                if options.on_error == ErrorPolicy::Continue && options.token.check().is_ok() =>
+            {
+                failed.push(format!("{rel}: {e}"))
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    progress.finish(wnd, &name, time);
+    if !failed.is_empty() {
+        anyhow::bail!("{} failed: {}", failed.len(), failed.join("; "));
+    }
     Ok(())
 }
 
 async fn upload_onefile(
     wnd: &tauri::Window,
+    session: &AsyncSession<TokioTcpStream>,
     sftp: &AsyncSftp<TokioTcpStream>,
     local_path: &Path,
-    remote_path: &Path,
-    ft: &Metadata,
+    remote_file: &Path,
+    options: &TransferOptions,
+    progress: &mut Progress,
 ) -> Result<()> {
-    let time = Instant::now();
     let file_name = local_path.file_name().unwrap_or_default().to_string_lossy();
-    let remote_file = remote_path.join(file_name.as_ref());
 
     let mut src = tokio::fs::File::open(local_path).await?;
-    let mut dst = sftp.create(&remote_file).await?;
+    let mut offset = 0;
+    if options.resume {
+        let meta = src.metadata().await?;
+        let dst = sftp
+            .stat(remote_file)
+            .await
+            .ok()
+            .map(|v| (v.size.unwrap_or_default(), v.mtime.unwrap_or_default()));
+        let verify;
+        (offset, verify) = resume_offset((meta.len(), local_mtime(&meta)), dst, options.verify);
+
+        if verify {
+            progress.verifying(wnd, &file_name);
+            if hash_local(local_path, offset).await?
+                != hash_remote(session, sftp, remote_file, offset).await?
+            {
+                offset = 0;
+            }
+        }
+    }
+
+    let mut dst = match offset {
+        0 => sftp.create(remote_file).await?,
+        _ => {
+            let mut f = sftp
+                .open_mode(remote_file, OpenFlags::WRITE, 0o644, OpenType::File)
+                .await?;
+            f.seek(SeekFrom::Start(offset)).await?;
+            f
+        }
+    };
+    src.seek(SeekFrom::Start(offset)).await?;
+    progress.skip(offset);
 
     let mut buf = vec![0u8; 256 * 1024];
     let data = buf.as_mut_slice();
-    let total_size = ft.len();
-    let mut rate = 0;
-    let mut now_size = 0;
 
-    while let Ok(n) = src.read(data).await {
+    loop {
+        options.token.check()?;
+        let n = src.read(data).await?;
         if n == 0 {
             break;
         }
 
-        now_size += n as u64;
         dst.write_all(&data[..n]).await?;
+        progress.advance(wnd, n as u64, &file_name);
+    }
 
-        if total_size == 0 {
-            continue;
-        }
+    dst.close().await?;
+    Ok(())
+}
 
-        let rate_tmp = now_size * 100 / total_size;
-        if rate_tmp == rate {
-            continue;
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        rate = rate_tmp;
-        let json_data = json!({
-            "rate": rate_tmp,
-            "message": file_name.to_string(),
-        });
-        wnd.emit(ENT_FTM, json_data).ok();
+    #[test]
+    fn resume_offset_destination() {
+        let src = (100, 1000);
+        assert_eq!(resume_offset(src, None, false), (0, false));
+        assert_eq!(resume_offset(src, None, true), (0, false));
+        assert_eq!(resume_offset(src, Some((0, 2000)), true), (0, false));
+        // 目标比源文件大, 不是已传部分
+        assert_eq!(resume_offset(src, Some((101, 2000)), false), (0, false));
+        assert_eq!(resume_offset(src, Some((101, 2000)), true), (0, false));
     }
 
-    if now_size >= total_size {
-        let json_data = json!({
-            "rate": 100,
-            "message": format!("{}, time:{} ms, size:{}", file_name, time.elapsed().as_millis(), total_size),
-        });
-        wnd.emit(ENT_FTM, json_data).ok();
+    #[test]
+    fn remote_hash_command() {
+        assert_eq!(shell_quote("/tmp/a b"), "'/tmp/a b'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+
+        let hex = "00ff".repeat(16);
+        let hash = parse_sha256(&format!("{hex}  -\n")).unwrap();
+        assert_eq!((hash[0], hash[1], hash[31]), (0x00, 0xff, 0xff));
+        assert!(parse_sha256("").is_none());
+        assert!(parse_sha256("abc  -").is_none());
+        assert!(parse_sha256(&"zz".repeat(32)).is_none());
+    }
+
+    #[test]
+    fn resume_offset_prefix() {
+        let src = (100, 1000);
+        assert_eq!(resume_offset(src, Some((40, 2000)), false), (40, false));
+        assert_eq!(resume_offset(src, Some((100, 1000)), false), (100, false));
+        assert_eq!(resume_offset(src, Some((40, 2000)), true), (40, true));
+        // 目标早于源文件修改, 只有校验时才续传
+        assert_eq!(resume_offset(src, Some((40, 500)), false), (0, false));
+        assert_eq!(resume_offset(src, Some((40, 500)), true), (40, true));
     }
-    Ok(())
 }
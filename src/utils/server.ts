@@ -9,6 +9,8 @@ export const ID_CFG_F_NAME: number = 6;
 export const ID_CFG_F_GRPS: number = 7;
 export const ID_CFG_S_DGRP: number = 8;
 export const ID_CFG_S_VALS: number = 9;
+export const ID_CFG_RECONN: number = 10;
+export const ID_CFG_SLOG: number = 11;
 
 export interface ServerItem {
     id: string,
@@ -23,6 +25,7 @@ export interface TerminalItem {
 
 export interface ServerConfig {
     proxy_addr: String,
+    proxy_scheme?: string,
     local_path: string,
     remote_path: string,
     file_name: string,
@@ -44,6 +47,29 @@ export interface ServerDetail {
     cert_pass: string,
     cert_path: string,
     use_proxy: boolean,
+    proxy?: string,
+    auth_mode?: string,
+    jump_hosts?: Array<string>,
+    tunnels?: Array<TunnelSpec>,
+    always_record?: boolean,
+    record_input?: boolean,
+}
+
+export interface TunnelSpec {
+    kind: string,
+    bind_host: string,
+    bind_port: number,
+    target_host?: string,
+    target_port?: number,
+    auto_start?: boolean,
+}
+
+export interface ProxyProfile {
+    name: string,
+    scheme: string,
+    addr: string,
+    username: string,
+    password: string,
 }
 
 export interface ServerGroup {
@@ -78,4 +104,16 @@ export class ServerMgr {
     async updateServer(id: string, server: ServerDetail): Promise<void> {
         await invoke('ssh_update_server', { id, server });
     }
+
+    async getProxies(): Promise<Array<ProxyProfile>> {
+        return await invoke<Array<ProxyProfile>>('ssh_get_proxies');
+    }
+
+    async saveProxy(proxy: ProxyProfile): Promise<void> {
+        await invoke('ssh_save_proxy', { proxy });
+    }
+
+    async delProxy(name: string): Promise<void> {
+        await invoke('ssh_del_proxy', { name });
+    }
 }
\ No newline at end of file
@@ -3,12 +3,25 @@ import { invoke, Channel } from '@tauri-apps/api/core'
 export const CMD_DATA: number = 0;
 export const CMD_RESIZE: number = 1;
 export const CMD_CLOSE: number = 2;
+export const CMD_PROMPT: number = 3;
+export const CMD_RECONNECTING: number = 4;
+export const CMD_RECONNECTED: number = 5;
+export const CMD_PLAY_END: number = 6;
 
 export interface SSHMessage {
     code: number,
     data: string
 }
 
+// CMD_PROMPT 消息和 tauri://SshPromptMessage 事件的内容
+export interface PromptRequest {
+    id: number,
+    server: string,
+    username: string,
+    instructions: string,
+    prompts: Array<{ text: string, echo: boolean }>
+}
+
 export class SSHClient {
     channelId: number;
     private readonly listeners: Array<(arg: SSHMessage) => void>
@@ -17,10 +30,12 @@ export class SSHClient {
         this.listeners = listeners
     }
 
+    // 连接过程中就可能收到认证提示, 监听需要在连接前注册
     static async connect(
         id: string,
+        listener?: (arg: SSHMessage) => void,
     ): Promise<SSHClient> {
-        const listeners: Array<(arg: SSHMessage) => void> = []
+        const listeners: Array<(arg: SSHMessage) => void> = listener === undefined ? [] : [listener]
         const onMessage = new Channel<SSHMessage>()
 
         onMessage.onmessage = (message: SSHMessage): void => {
@@ -33,6 +48,20 @@ export class SSHClient {
             new SSHClient(cid, listeners))
     }
 
+    async duplicate(): Promise<SSHClient> {
+        const listeners: Array<(arg: SSHMessage) => void> = []
+        const onMessage = new Channel<SSHMessage>()
+
+        onMessage.onmessage = (message: SSHMessage): void => {
+            listeners.forEach((l) => {
+                l(message)
+            })
+        }
+
+        return await invoke<number>('ssh_duplicate', { id: this.channelId, onMessage: onMessage }).then((cid) =>
+            new SSHClient(cid, listeners))
+    }
+
     addListener(cb: (arg: SSHMessage) => void): void {
         this.listeners.push(cb)
     }